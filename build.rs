@@ -0,0 +1,29 @@
+//! Capture build metadata for the `version` command.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Run a command, returning its trimmed standard output if it succeeds.
+fn output(program: impl AsRef<std::ffi::OsStr>, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // Not available when building from a published crate.
+    for path in [".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    let git = output("git", &["rev-parse", "--short", "HEAD"]);
+    let rustc = output(env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()), &["--version"]);
+    println!("cargo:rustc-env=BRIGHTER_GIT_HASH={}", git.unwrap_or_default());
+    println!("cargo:rustc-env=BRIGHTER_RUSTC_VERSION={}", rustc.unwrap_or_default());
+}
@@ -0,0 +1,94 @@
+//! Default values for command line arguments, read from a configuration file.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::device::{Class, PathError};
+use crate::percent::Percent;
+use crate::{BIN_NAME, OutputFormat};
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathError),
+    Parse { path: PathBuf, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IO(error) => error.fmt(f),
+            Self::Parse { path, message } => {
+                write!(f, "invalid configuration file \"{}\": {message}", path.display())
+            }
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub default_class: Option<Class>,
+    pub default_format: Option<OutputFormat>,
+    pub default_step: Option<Percent>,
+}
+
+impl Config {
+    /// Load the configuration file, a missing file gives the default configuration.
+    pub fn load() -> Result<Self, Error> {
+        let Some(path) = get_config_path() else {
+            return Ok(Self::default());
+        };
+        log::info!("reading configuration from {}", path.display());
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(Error::IO(PathError::new(err, path))),
+        };
+        Self::parse(&content).map_err(|message| Error::Parse { path, message })
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        toml::from_str(content).map_err(|err| err.to_string().trim_end().to_string())
+    }
+}
+
+fn get_config_path() -> Option<PathBuf> {
+    let path = env::var_os("XDG_CONFIG_HOME");
+    log::info!("XDG_CONFIG_HOME = {path:?}");
+    path.filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| Some(env::home_dir()?.join(".config")))
+        .map(|p| p.join(BIN_NAME).join("config.toml"))
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::parse(
+        r#"
+        default_class = "leds"
+        default_format = "json-lines"
+        default_step = 2.5
+        "#,
+    )
+    .unwrap();
+    assert!(matches!(config.default_class, Some(Class::Leds)));
+    assert!(matches!(config.default_format, Some(OutputFormat::JsonLines)));
+    assert_eq!(config.default_step, Percent::new(2.5));
+
+    let config = Config::parse("default_step = 10").unwrap();
+    assert_eq!(config.default_step, Percent::new(10.0));
+
+    assert!(Config::parse("").is_ok());
+    assert!(Config::parse("default_class = 1").is_err());
+    assert!(Config::parse(r#"default_format = "xml""#).is_err());
+    assert!(Config::parse("default_step = 101").is_err());
+    assert!(Config::parse("default_stpe = 5").is_err());
+}
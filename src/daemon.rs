@@ -0,0 +1,243 @@
+//! Resident process that listens for brightness changes and broadcasts them to clients
+//! connected to a Unix socket, as JSON lines.
+
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{fs, mem, thread};
+
+use crate::DeviceOutput;
+use crate::device::{self, Class, Device, DeviceCache, DeviceFilters, PathError};
+
+type Clients = Arc<Mutex<Vec<UnixStream>>>;
+
+/// Open a netlink socket subscribed to kernel uevents.
+fn uevent_socket() -> io::Result<File> {
+    // SAFETY: plain system call, the returned descriptor is checked below.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a valid descriptor owned by nobody else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: `sockaddr_nl` is valid when zeroed.
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = 1; // Kernel uevents multicast group
+    // SAFETY: `addr` is a valid `sockaddr_nl` and its size is given.
+    let res = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&raw const addr).cast(),
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(File::from(fd))
+}
+
+/// Returns the device under `sysfs_root` affected by a uevent message, if it belongs to
+/// a known class.
+///
+/// Messages are NUL separated, the first field is `ACTION@DEVPATH` and the rest are
+/// `KEY=VALUE` pairs.
+fn uevent_device_path(msg: &[u8], sysfs_root: &Path) -> Option<PathBuf> {
+    let mut subsystem = None;
+    let mut devpath = None;
+    for field in msg
+        .split(|&b| b == 0)
+        .filter_map(|f| str::from_utf8(f).ok())
+    {
+        if let Some(value) = field.strip_prefix("SUBSYSTEM=") {
+            subsystem = Some(value);
+        } else if let Some(value) = field.strip_prefix("DEVPATH=") {
+            devpath = Some(value);
+        }
+    }
+    let class = match subsystem? {
+        "backlight" => Class::Backlight,
+        "leds" => Class::Leds,
+        _ => return None,
+    };
+    let name = Path::new(devpath?).file_name()?;
+    Some(class.prefix(sysfs_root).join(name))
+}
+
+fn write_device(stream: &mut impl Write, device: Device) -> io::Result<()> {
+    serde_json::to_writer(&mut *stream, &DeviceOutput::from(device))?;
+    stream.write_all(b"\n")
+}
+
+/// Send a device to all clients, dropping the ones that disconnected.
+fn broadcast(clients: &Clients, device: Device) {
+    let mut line = Vec::new();
+    if let Err(err) = write_device(&mut line, device) {
+        log::error!("{err}");
+        return;
+    }
+    let mut clients = clients.lock().expect("lock not to be poisoned");
+    clients.retain_mut(|client| client.write_all(&line).is_ok());
+}
+
+fn accept_clients(listener: UnixListener, clients: Clients, filters: DeviceFilters) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("failed to accept client: {err}");
+                continue;
+            }
+        };
+        log::info!("client connected");
+        // Send the current state first, so clients don't have to wait for a change.
+        let sent = match device::get_devices(&filters, &DeviceCache::default()) {
+            Ok(mut devices) => devices
+                .try_for_each(|device| write_device(&mut stream, device))
+                .is_ok(),
+            Err(err) => {
+                log::warn!("{err}");
+                true
+            }
+        };
+        if sent {
+            clients
+                .lock()
+                .expect("lock not to be poisoned")
+                .push(stream);
+        }
+    }
+}
+
+/// Remove a socket left behind by a previous instance at `path`. Anything else is left
+/// alone, since it may be a file given by mistake.
+fn remove_stale_socket(path: &Path) -> Result<(), PathError> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(|err| PathError::new(err, path))
+        }
+        Ok(_) => Err(PathError::new(
+            io::Error::new(io::ErrorKind::AlreadyExists, "not a socket, refusing to replace it"),
+            path,
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(PathError::new(err, path)),
+    }
+}
+
+/// Path of the socket to remove when terminated by a signal.
+static SOCKET_PATH: OnceLock<CString> = OnceLock::new();
+
+extern "C" fn remove_socket_and_exit(signal: libc::c_int) {
+    // SAFETY: only async-signal-safe functions are called, with a valid C string.
+    unsafe {
+        if let Some(path) = SOCKET_PATH.get() {
+            libc::unlink(path.as_ptr());
+        }
+        // Terminate the same way as without the handler.
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Removes the socket when dropped, i.e. when the daemon stops because of an error.
+struct SocketFile<'a>(&'a Path);
+
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(self.0) {
+            log::warn!("{}", PathError::new(err, self.0));
+        }
+    }
+}
+
+/// Listen for changes on devices matching `filters` until an error occurs or the process
+/// is interrupted, removing the socket at `socket_path` on exit.
+pub fn run(socket_path: &Path, filters: DeviceFilters) -> Result<(), Box<dyn Error>> {
+    let mut uevents = uevent_socket()?;
+
+    remove_stale_socket(socket_path)?;
+    let listener =
+        UnixListener::bind(socket_path).map_err(|err| PathError::new(err, socket_path))?;
+    let _socket_file = SocketFile(socket_path);
+    if let Ok(path) = CString::new(socket_path.as_os_str().as_bytes()) {
+        _ = SOCKET_PATH.set(path);
+        let handler = remove_socket_and_exit as extern "C" fn(libc::c_int);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the handler only calls async-signal-safe functions.
+            unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        }
+    }
+    log::info!("listening on {}", socket_path.display());
+
+    let clients = Clients::default();
+    thread::spawn({
+        let clients = Arc::clone(&clients);
+        let filters = filters.clone();
+        move || accept_clients(listener, clients, filters)
+    });
+
+    let mut buf = vec![0; 8192];
+    loop {
+        let n = uevents.read(&mut buf)?;
+        let Some(path) = uevent_device_path(&buf[..n], filters.sysfs_root()) else {
+            continue;
+        };
+        log::debug!("uevent for {}", path.display());
+        match Device::from_path(path) {
+            Ok(device) if filters.matches(&device) => broadcast(&clients, device),
+            Ok(_) => {}
+            Err(err) => log::warn!("{err}"),
+        }
+    }
+}
+
+#[test]
+fn test_uevent_device_path() {
+    let msg = b"change@/devices/pci0000:00/0000:00:02.0/drm/card1/card1-eDP-1/intel_backlight\0\
+        ACTION=change\0\
+        DEVPATH=/devices/pci0000:00/0000:00:02.0/drm/card1/card1-eDP-1/intel_backlight\0\
+        SUBSYSTEM=backlight\0\
+        SOURCE=sysfs\0";
+    assert_eq!(
+        uevent_device_path(msg, Path::new(device::SYSFS_ROOT)),
+        Some(PathBuf::from("/sys/class/backlight/intel_backlight"))
+    );
+
+    let msg = b"add@/devices/virtual/net/lo\0ACTION=add\0DEVPATH=/devices/virtual/net/lo\0\
+        SUBSYSTEM=net\0";
+    assert_eq!(uevent_device_path(msg, Path::new(device::SYSFS_ROOT)), None);
+}
+
+#[test]
+fn test_remove_stale_socket() {
+    let dir = device::test::mock_root("stale_socket");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("daemon.sock");
+    remove_stale_socket(&path).unwrap();
+
+    let listener = UnixListener::bind(&path).unwrap();
+    drop(listener);
+    remove_stale_socket(&path).unwrap();
+    assert!(!path.exists());
+
+    // Other files aren't replaced
+    fs::write(&path, "notes").unwrap();
+    assert!(remove_stale_socket(&path).is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "notes");
+}
@@ -0,0 +1,140 @@
+//! External monitors controlled through DDC/CI, using the `ddcutil` command.
+//!
+//! Displays are identified by the path of their I2C bus (e.g. `/dev/i2c-4`).
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::device::{Brightness, Class, Device, PathError};
+
+/// VCP feature code for the brightness (luminance) of a display.
+const BRIGHTNESS_VCP: &str = "10";
+
+/// Run `ddcutil` with `args`, returning its standard output.
+fn ddcutil(args: &[&str]) -> io::Result<String> {
+    log::debug!("running ddcutil {}", args.join(" "));
+    let output = Command::new("ddcutil").args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "ddcutil exited with {}: {}",
+            output.status,
+            stderr.trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the bus number of an I2C device path, e.g. `4` for `/dev/i2c-4`.
+fn bus_number(path: &Path) -> io::Result<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("i2c-"))
+        .ok_or_else(|| io::Error::other("not an I2C device"))
+}
+
+/// Parse the output of `ddcutil detect --terse`, returning the I2C bus of valid displays.
+fn parse_detect(output: &str) -> Vec<PathBuf> {
+    let mut buses = Vec::new();
+    let mut valid = false;
+    for line in output.lines().map(str::trim) {
+        if line.starts_with("Display ") {
+            valid = true;
+        } else if line.starts_with("Invalid display") {
+            valid = false;
+        } else if let Some(bus) = line.strip_prefix("I2C bus:")
+            && valid
+        {
+            buses.push(PathBuf::from(bus.trim()));
+        }
+    }
+    buses
+}
+
+/// Parse the output of `ddcutil getvcp 10 --brief`, e.g. `VCP 10 C 50 100`, returning
+/// the current and max values.
+fn parse_getvcp(output: &str) -> Option<(Brightness, Brightness)> {
+    match output.split_whitespace().collect::<Vec<_>>()[..] {
+        ["VCP", BRIGHTNESS_VCP, "C", current, max] => {
+            Some((current.parse().ok()?, max.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Paths of all displays that support DDC/CI. Returns nothing if `ddcutil` can't be run.
+pub fn detect() -> Vec<PathBuf> {
+    match ddcutil(&["detect", "--terse"]) {
+        Ok(output) => parse_detect(&output),
+        Err(err) => {
+            log::warn!("failed to detect DDC/CI displays: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Read the brightness of the display connected to the I2C bus at `path`.
+pub fn read_device(path: PathBuf) -> Result<Device, PathError> {
+    let bus = bus_number(&path).map_err(|err| PathError::new(err, &path))?;
+    let (brightness, max_brightness) =
+        ddcutil(&["--bus", bus, "getvcp", BRIGHTNESS_VCP, "--brief"])
+            .and_then(|output| {
+                parse_getvcp(&output).ok_or_else(|| {
+                    io::Error::other(format!("unexpected ddcutil output: {output:?}"))
+                })
+            })
+            .map_err(|err| PathError::new(err, &path))?;
+
+    Ok(Device {
+        name: path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        path,
+        class: Class::Ddc,
+        brightness: brightness.min(max_brightness),
+        max_brightness,
+        multi_intensity: None,
+        device_type: None,
+        scale: None,
+        trigger: None,
+    })
+}
+
+/// Set the brightness of a display.
+pub fn set_brightness(device: &Device, value: Brightness) -> Result<(), PathError> {
+    let bus = bus_number(&device.path).map_err(|err| PathError::new(err, &device.path))?;
+    ddcutil(&["--bus", bus, "setvcp", BRIGHTNESS_VCP, &value.to_string()])
+        .map_err(|err| PathError::new(err, &device.path))?;
+    Ok(())
+}
+
+#[test]
+fn test_parse_detect() {
+    let output = "\
+Display 1
+   I2C bus:  /dev/i2c-4
+   DRM connector:           card1-DP-1
+   Monitor:                 DEL:DELL U2720Q:ABC123
+
+Invalid display
+   I2C bus:  /dev/i2c-5
+   Monitor:                 BOE::
+
+Display 2
+   I2C bus:  /dev/i2c-7
+";
+    assert_eq!(parse_detect(output), [PathBuf::from("/dev/i2c-4"), PathBuf::from("/dev/i2c-7")]);
+    assert!(parse_detect("No displays found.").is_empty());
+}
+
+#[test]
+fn test_parse_getvcp() {
+    assert_eq!(parse_getvcp("VCP 10 C 50 100\n"), Some((50, 100)));
+    assert_eq!(parse_getvcp("VCP 10 ERR"), None);
+    assert_eq!(parse_getvcp("VCP 12 C 50 100"), None);
+    assert_eq!(bus_number(Path::new("/dev/i2c-4")).unwrap(), "4");
+    assert!(bus_number(Path::new("/dev/null")).is_err());
+}
@@ -1,27 +1,46 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Display;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::ValueEnum;
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use zbus::zvariant::Type;
 
-pub use controller::Controller;
+pub use controller::{
+    BrightnessBackend, Controller, ControllerOptions, LazyController, WriteMethod,
+};
 
-mod controller {
-    use std::fmt;
+pub mod controller {
+    use std::cell::OnceCell;
+    use std::collections::HashMap;
+    use std::process::Command;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::{env, fmt, io, thread};
 
+    use clap::ValueEnum;
     use zbus::blocking::connection::Connection;
-    use zbus::proxy;
+    use zbus::zvariant::Value;
+    use zbus::{DBusError, proxy};
 
     use super::{Brightness, Class, Device, PathError};
+    use crate::BIN_NAME;
+    use crate::percent::Percent;
 
     #[derive(Debug)]
     pub enum Error {
         IO(PathError),
         DBus(zbus::Error),
+        Timeout(Duration),
+        Escalate(String),
     }
 
     impl From<zbus::Error> for Error {
@@ -41,6 +60,12 @@ mod controller {
             match self {
                 Error::IO(error) => error.fmt(f),
                 Error::DBus(error) => error.fmt(f),
+                Error::Timeout(timeout) => {
+                    write!(f, "timed out connecting to D-Bus after {}ms", timeout.as_millis())
+                }
+                Error::Escalate(msg) => {
+                    write!(f, "failed to write with elevated privileges: {msg}")
+                }
             }
         }
     }
@@ -58,32 +83,400 @@ mod controller {
         fn set_brightness(&self, class: Class, name: &str, brightness: u32) -> zbus::Result<()>;
     }
 
-    pub struct Controller(Option<Connection>);
+    #[proxy(
+        default_service = "org.freedesktop.Notifications",
+        default_path = "/org/freedesktop/Notifications",
+        interface = "org.freedesktop.Notifications"
+    )]
+    trait Notifications {
+        // Needs to be connected to the session bus.
+        // See: https://specifications.freedesktop.org/notification-spec/latest/protocol.html
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: &str,
+            replaces_id: u32,
+            app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: &[&str],
+            hints: HashMap<&str, Value<'_>>,
+            expire_timeout: i32,
+        ) -> zbus::Result<u32>;
+    }
+
+    #[proxy(
+        default_service = "org.freedesktop.UPower",
+        default_path = "/org/freedesktop/UPower",
+        interface = "org.freedesktop.UPower"
+    )]
+    trait UPower {
+        // Needs to be connected to the system bus.
+        // See: https://upower.freedesktop.org/docs/UPower.html
+        #[zbus(property)]
+        fn on_battery(&self) -> zbus::Result<bool>;
+    }
+
+    /// How brightness values are written to devices.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+    pub enum WriteMethod {
+        /// Use D-Bus if the system bus is available, otherwise write to sysfs.
+        #[default]
+        Auto,
+        /// Always write directly to sysfs.
+        Sysfs,
+        /// Always use D-Bus, fail if the system bus is not available.
+        Dbus,
+    }
+
+    /// How a brightness value was actually written.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Backend {
+        /// `SetBrightness()` of logind.
+        Dbus,
+        /// Written directly to sysfs.
+        Sysfs,
+        /// Written to sysfs by this program run through `pkexec`.
+        Pkexec,
+        Ddcutil,
+    }
+
+    impl fmt::Display for Backend {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Backend::Dbus => write!(f, "dbus"),
+                Backend::Sysfs => write!(f, "sysfs"),
+                Backend::Pkexec => write!(f, "pkexec"),
+                Backend::Ddcutil => write!(f, "ddcutil"),
+            }
+        }
+    }
+
+    /// Something that can change the brightness of devices, real or not.
+    pub trait BrightnessBackend {
+        /// Set the brightness of `device`, updating its current value. Returns how the
+        /// value was written.
+        fn set(&self, device: &mut Device, value: Brightness) -> Result<Backend, Error>;
+
+        /// Set the per-channel intensities of a multicolor LED.
+        fn set_multi_intensity(
+            &self,
+            device: &mut Device,
+            values: Vec<Brightness>,
+        ) -> Result<(), Error>;
+
+        /// Show the new brightness in a desktop notification, if enabled.
+        fn notify(&self, _percent: Percent) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct ControllerOptions {
+        pub method: WriteMethod,
+        /// Retry writes to sysfs through `pkexec` when permission is denied.
+        pub escalate: bool,
+        /// How long to wait for the connection to the system or session bus.
+        pub timeout: Duration,
+        /// Show a desktop notification after changing the brightness.
+        pub notify: bool,
+        /// How many times to retry failed writes before giving up.
+        pub retries: u32,
+    }
+
+    impl ControllerOptions {
+        pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+    }
+
+    impl Default for ControllerOptions {
+        fn default() -> Self {
+            Self {
+                method: WriteMethod::default(),
+                escalate: false,
+                timeout: Self::DEFAULT_TIMEOUT,
+                notify: false,
+                retries: 0,
+            }
+        }
+    }
+
+    /// Wait before the first retry, doubled after each attempt.
+    const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+    /// Call `write` until it succeeds, up to `retries` more times while `should_retry`
+    /// returns true for the error.
+    fn retry<E: fmt::Display>(
+        retries: u32,
+        should_retry: impl Fn(&E) -> bool,
+        mut write: impl FnMut() -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=retries {
+            match write() {
+                Err(err) if should_retry(&err) => {
+                    log::debug!(
+                        "write failed ({err}), retrying in {}ms ({attempt}/{retries})",
+                        backoff.as_millis()
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                res => return res,
+            }
+        }
+        write()
+    }
+
+    /// Names of D-Bus errors that may not happen again, e.g. when logind is busy or being
+    /// restarted.
+    const TRANSIENT_DBUS_ERRORS: [&str; 5] = [
+        "org.freedesktop.DBus.Error.NoReply",
+        "org.freedesktop.DBus.Error.Timeout",
+        "org.freedesktop.DBus.Error.TimedOut",
+        "org.freedesktop.DBus.Error.ServiceUnknown",
+        "org.freedesktop.DBus.Error.LimitsExceeded",
+    ];
+
+    /// Whether a D-Bus call failing with `err` may succeed if made again. Errors such as
+    /// `AccessDenied` or `UnknownMethod` won't go away by retrying.
+    pub(super) fn is_transient(err: &zbus::Error) -> bool {
+        match err {
+            zbus::Error::InputOutput(_) => true,
+            zbus::Error::MethodError(name, ..) => TRANSIENT_DBUS_ERRORS.contains(&name.as_str()),
+            zbus::Error::FDO(err) => TRANSIENT_DBUS_ERRORS.contains(&err.name().as_str()),
+            _ => false,
+        }
+    }
+
+    /// Connect to a bus (e.g. [`Connection::system`]), giving up after `timeout`.
+    fn connect(
+        bus: fn() -> zbus::Result<Connection>,
+        timeout: Duration,
+    ) -> Result<Connection, Error> {
+        let (sender, receiver) = mpsc::channel();
+        // The thread is left behind on timeout, it doesn't hold any resources that
+        // matter for a short lived process.
+        thread::spawn(move || _ = sender.send(bus()));
+        match receiver.recv_timeout(timeout) {
+            Ok(connection) => Ok(connection?),
+            Err(_) => Err(Error::Timeout(timeout)),
+        }
+    }
+
+    pub struct Controller {
+        connection: Option<Connection>,
+        escalate: bool,
+        retries: u32,
+    }
 
     impl Controller {
-        pub fn new() -> Self {
-            let connection = Connection::system().inspect_err(|err| {
-                log::warn!("failed to connect to system bus: {err}");
-            });
-            Self(connection.ok())
+        pub fn new(options: ControllerOptions) -> Result<Self, Error> {
+            let connection = match options.method {
+                WriteMethod::Auto => connect(Connection::system, options.timeout)
+                    .inspect_err(|err| log::warn!("failed to connect to system bus: {err}"))
+                    .ok(),
+                WriteMethod::Sysfs => None,
+                WriteMethod::Dbus => Some(connect(Connection::system, options.timeout)?),
+            };
+            Ok(Self {
+                connection,
+                escalate: options.escalate,
+                retries: options.retries,
+            })
         }
 
-        pub fn set_brightness(&self, device: &mut Device, value: Brightness) -> Result<(), Error> {
+        /// Set the brightness of `device`, returning how it was written.
+        pub fn set_brightness(
+            &self,
+            device: &mut Device,
+            value: Brightness,
+        ) -> Result<Backend, Error> {
             let brightness = value.min(device.max_brightness);
-            if let Some(connection) = &self.0 {
-                log::debug!("setting brightness using D-Bus");
+            if brightness != value {
+                log::warn!(
+                    r#"{value} is above the max brightness of device "{}", setting it to {brightness}"#,
+                    device.name
+                );
+            }
+            let backend = if device.class == Class::Ddc {
+                log::debug!("setting brightness using ddcutil");
+                crate::ddc::set_brightness(device, brightness)?;
+                Backend::Ddcutil
+            } else if let Some(connection) = &self.connection {
+                let unique_name = connection.unique_name().map(|name| name.as_str());
+                log::debug!(
+                    "setting brightness using D-Bus (connection: {}): SetBrightness({:?}, {:?}, {brightness})",
+                    unique_name.unwrap_or("<unknown>"),
+                    device.class.to_string(),
+                    device.name,
+                );
                 let proxy = SessionProxyBlocking::new(connection)?;
-                proxy.set_brightness(device.class, &device.name, u32::from(value))?;
+                let result = retry(self.retries, is_transient, || {
+                    proxy.set_brightness(device.class, &device.name, brightness)
+                });
+                match &result {
+                    Ok(()) => log::debug!("SetBrightness call returned successfully"),
+                    Err(err) => log::debug!("SetBrightness call failed: {err}"),
+                }
+                result?;
+                Backend::Dbus
             } else {
-                let path = device.path.join("brightness");
-                log::debug!("setting brightness by writing to {}", path.display());
-                std::fs::write(&path, value.to_string())
-                    .map_err(|err| PathError::new(err, path))?;
-            }
+                // Permission errors won't go away by retrying, they are escalated instead.
+                let result = retry(
+                    self.retries,
+                    |err: &PathError| err.error.kind() != io::ErrorKind::PermissionDenied,
+                    || device.set_brightness_sysfs(brightness),
+                );
+                match result {
+                    Err(err)
+                        if err.error.kind() == io::ErrorKind::PermissionDenied && self.escalate =>
+                    {
+                        log::info!(
+                            "permission denied for {}, retrying with pkexec",
+                            err.path.display()
+                        );
+                        write_with_pkexec(device, brightness)?;
+                        Backend::Pkexec
+                    }
+                    res => {
+                        res?;
+                        Backend::Sysfs
+                    }
+                }
+            };
+            log::info!(r#"brightness of device "{}" written using {backend}"#, device.name);
             device.brightness = brightness;
+            Ok(backend)
+        }
+
+        /// Write per-channel intensities of a multicolor LED. There's no D-Bus method for
+        /// this, so it's always written directly to sysfs.
+        pub fn set_multi_intensity(
+            &self,
+            device: &mut Device,
+            values: Vec<Brightness>,
+        ) -> Result<(), Error> {
+            let path = device.path.join("multi_intensity");
+            log::debug!("setting multi intensity by writing to {}", path.display());
+            let content = values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            std::fs::write(&path, content).map_err(|err| PathError::new(err, path))?;
+            device.multi_intensity = Some(values);
             Ok(())
         }
     }
+
+    impl BrightnessBackend for Controller {
+        fn set(&self, device: &mut Device, value: Brightness) -> Result<Backend, Error> {
+            self.set_brightness(device, value)
+        }
+
+        fn set_multi_intensity(
+            &self,
+            device: &mut Device,
+            values: Vec<Brightness>,
+        ) -> Result<(), Error> {
+            Controller::set_multi_intensity(self, device, values)
+        }
+    }
+
+    /// A [`Controller`] created on first use, so that commands that never write brightness
+    /// don't connect to the system bus.
+    pub struct LazyController {
+        options: ControllerOptions,
+        controller: OnceCell<Controller>,
+    }
+
+    impl LazyController {
+        pub fn new(options: ControllerOptions) -> Self {
+            Self {
+                options,
+                controller: OnceCell::new(),
+            }
+        }
+
+        pub fn get(&self) -> Result<&Controller, Error> {
+            if let Some(controller) = self.controller.get() {
+                return Ok(controller);
+            }
+            let controller = Controller::new(self.options)?;
+            Ok(self.controller.get_or_init(|| controller))
+        }
+
+        /// Whether the system is running on battery, according to UPower. Uses the
+        /// connection of the controller if it has one.
+        pub fn on_battery(&self) -> Result<bool, Error> {
+            let connection = match &self.get()?.connection {
+                Some(connection) => connection.clone(),
+                None => connect(Connection::system, self.options.timeout)?,
+            };
+            let on_battery = UPowerProxyBlocking::new(&connection)?.on_battery()?;
+            log::info!("on battery = {on_battery}");
+            Ok(on_battery)
+        }
+
+        /// Whether the controller has been created.
+        #[cfg(test)]
+        pub fn is_initialized(&self) -> bool {
+            self.controller.get().is_some()
+        }
+    }
+
+    impl BrightnessBackend for LazyController {
+        fn set(&self, device: &mut Device, value: Brightness) -> Result<Backend, Error> {
+            self.get()?.set_brightness(device, value)
+        }
+
+        fn set_multi_intensity(
+            &self,
+            device: &mut Device,
+            values: Vec<Brightness>,
+        ) -> Result<(), Error> {
+            self.get()?.set_multi_intensity(device, values)
+        }
+
+        fn notify(&self, percent: Percent) -> Result<(), Error> {
+            if !self.options.notify {
+                return Ok(());
+            }
+            let connection = connect(Connection::session, self.options.timeout)?;
+            let proxy = NotificationsProxyBlocking::new(&connection)?;
+            let value = percent.get().round() as i32;
+            let hints = HashMap::from([
+                // Progress bar
+                ("value", Value::from(value)),
+                // Replace the previous notification instead of stacking them
+                ("x-canonical-private-synchronous", Value::from(BIN_NAME)),
+            ]);
+            let summary = format!("Brightness {value}%");
+            log::debug!("sending notification: {summary:?}");
+            proxy.notify(BIN_NAME, 0, "display-brightness", &summary, "", &[], hints, -1)?;
+            Ok(())
+        }
+    }
+
+    /// Re-invoke this program under `pkexec` to write the brightness directly to sysfs.
+    fn write_with_pkexec(device: &Device, value: Brightness) -> Result<(), Error> {
+        let exe = env::current_exe().map_err(|err| Error::Escalate(err.to_string()))?;
+        let mut command = Command::new("pkexec");
+        command
+            .arg(exe)
+            .args(["--write-method", "sysfs", "write-brightness"])
+            .arg(&device.path)
+            .arg(value.to_string());
+        log::debug!("running {command:?}");
+        let status = command
+            .status()
+            .map_err(|err| Error::Escalate(format!("pkexec: {err}")))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::Escalate(format!("pkexec exited with {status}")))
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -109,19 +502,32 @@ impl fmt::Display for PathError {
 
 impl core::error::Error for PathError {}
 
-#[derive(Debug, Clone, Copy, Type, ValueEnum, Serialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Type, ValueEnum, Serialize, Deserialize, JsonSchema,
+)]
 #[serde(rename_all = "lowercase")]
 #[zvariant(signature = "s")]
 pub enum Class {
     Leds,
     Backlight,
+    /// External displays controlled through DDC/CI.
+    Ddc,
 }
 
+/// Directory in sysfs containing a directory for each class of devices.
+pub const SYSFS_ROOT: &str = "/sys/class";
+
+/// Directory containing the I2C buses of DDC/CI displays.
+const DEV_DIR: &str = "/dev";
+
 impl Class {
-    const fn prefix(self) -> &'static str {
+    /// Directory containing all devices of this class. Devices are in `sysfs_root`,
+    /// except for DDC/CI displays which are the I2C buses they are connected to.
+    pub fn prefix(self, sysfs_root: &Path) -> PathBuf {
         match self {
-            Self::Leds => "/sys/class/leds",
-            Self::Backlight => "/sys/class/backlight",
+            Self::Leds => sysfs_root.join("leds"),
+            Self::Backlight => sysfs_root.join("backlight"),
+            Self::Ddc => PathBuf::from(DEV_DIR),
         }
     }
 }
@@ -131,12 +537,40 @@ impl Display for Class {
         match self {
             Class::Leds => write!(f, "leds"),
             Class::Backlight => write!(f, "backlight"),
+            Class::Ddc => write!(f, "ddc"),
+        }
+    }
+}
+
+/// Error parsing a [`Class`] from a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseClassError(String);
+
+impl fmt::Display for ParseClassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown device class {:?}, expected leds, backlight or ddc", self.0)
+    }
+}
+
+impl core::error::Error for ParseClassError {}
+
+/// Parses the names given by [`Display`], independently of clap.
+impl FromStr for Class {
+    type Err = ParseClassError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leds" => Ok(Self::Leds),
+            "backlight" => Ok(Self::Backlight),
+            "ddc" => Ok(Self::Ddc),
+            _ => Err(ParseClassError(s.to_string())),
         }
     }
 }
 
-pub type Brightness = u16;
+pub type Brightness = u32;
 
+#[derive(Clone)]
 pub struct Device {
     /// Device name, derived from its path.
     pub name: String,
@@ -145,13 +579,33 @@ pub struct Device {
     pub class: Class,
     pub brightness: Brightness,
     pub max_brightness: Brightness,
+    /// Per-channel intensities for multicolor LEDs (e.g. RGB).
+    pub multi_intensity: Option<Vec<Brightness>>,
+    /// Value of the `type` attribute, for backlights one of `raw`, `platform` or
+    /// `firmware`.
+    pub device_type: Option<String>,
+    /// Value of the `scale` attribute of backlights, how raw values relate to the
+    /// perceived brightness: `linear`, `non-linear` or `unknown`.
+    pub scale: Option<String>,
+    /// Active trigger of LEDs that support them (e.g. `kbd-capslock` or `none`).
+    pub trigger: Option<String>,
 }
 
 impl Device {
     pub fn from_path(prefix: impl Into<PathBuf>) -> Result<Device, PathError> {
-        fn inner(path: PathBuf) -> Result<Device, PathError> {
+        Self::read(prefix, false)
+    }
+
+    /// Like [`Device::from_path`], but with `strict` a brightness that can't be read is an
+    /// error instead of being assumed to be 0.
+    fn read(prefix: impl Into<PathBuf>, strict: bool) -> Result<Device, PathError> {
+        fn inner(path: PathBuf, strict: bool) -> Result<Device, PathError> {
             log::debug!("creating device from path: {}", path.display());
 
+            if path.parent() == Some(Path::new(DEV_DIR)) {
+                return crate::ddc::read_device(path);
+            }
+
             let name = path
                 .file_name()
                 .ok_or_else(|| {
@@ -163,8 +617,20 @@ impl Device {
             // Available paths to device properties
             // https://www.kernel.org/doc/html/latest/admin-guide/abi-stable-files.html#abi-file-stable-sysfs-class-backlight
 
-            let brightness = parse_brightness(&path.join("brightness"))?;
             let max_brightness = parse_brightness(&path.join("max_brightness"))?;
+            // Some LEDs can be written but not read, they're kept so they can still be set.
+            let brightness = match parse_brightness(&path.join("brightness")) {
+                Err(err) if err.error.kind() == io::ErrorKind::PermissionDenied && !strict => {
+                    log::warn!("{err}, assuming a brightness of 0");
+                    0
+                }
+                res => res?,
+            };
+            let multi_intensity = parse_multi_intensity(&path.join("multi_intensity"))?;
+            let device_type = read_attribute(&path.join("type"))?;
+            let scale = read_attribute(&path.join("scale"))?;
+            let trigger = read_attribute(&path.join("trigger"))?
+                .and_then(|content| parse_triggers(&content).1.map(str::to_string));
 
             assert!(
                 brightness <= max_brightness,
@@ -186,37 +652,244 @@ impl Device {
                 class,
                 brightness,
                 max_brightness,
+                multi_intensity,
+                device_type,
+                scale,
+                trigger,
             })
         }
-        inner(prefix.into())
+        inner(prefix.into(), strict)
+    }
+
+    /// Use the value of `actual_brightness` as the current brightness, which can differ
+    /// from the last value set (e.g. with hardware auto-brightness). Devices without it,
+    /// like LEDs, keep their current brightness.
+    pub fn read_actual_brightness(&mut self) -> Result<(), PathError> {
+        let path = self.path.join("actual_brightness");
+        match parse_brightness(&path) {
+            Ok(brightness) => self.brightness = brightness.min(self.max_brightness),
+            Err(err) if err.error.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Whether the brightness can be written directly, by opening the file it's written to
+    /// for writing. Doesn't account for writing through D-Bus.
+    pub fn is_writable(&self) -> bool {
+        let path = if self.class == Class::Ddc {
+            self.path.clone()
+        } else {
+            self.path.join("brightness")
+        };
+        fs::OpenOptions::new().write(true).open(path).is_ok()
+    }
+
+    /// Number of discrete hardware levels, including off.
+    pub fn levels(&self) -> Brightness {
+        self.max_brightness + 1
+    }
+
+    /// Triggers supported by an LED, empty if it doesn't support them.
+    pub fn triggers(&self) -> Result<Vec<String>, PathError> {
+        let content = read_attribute(&self.path.join("trigger"))?;
+        let triggers = content
+            .as_deref()
+            .map(|content| parse_triggers(content).0)
+            .unwrap_or_default();
+        Ok(triggers.into_iter().map(str::to_string).collect())
+    }
+
+    /// Activate a trigger of an LED, `none` deactivates the current one.
+    pub fn set_trigger(&mut self, name: &str) -> Result<(), PathError> {
+        let path = self.path.join("trigger");
+        let triggers = self.triggers()?;
+        if triggers.is_empty() {
+            let error = io::Error::other("device doesn't support triggers");
+            return Err(PathError::new(error, &self.path));
+        }
+        if !triggers.iter().any(|trigger| trigger == name) {
+            let error = io::Error::other(format!(
+                "unknown trigger {name:?}, available: {}",
+                triggers.join(", ")
+            ));
+            return Err(PathError::new(error, path));
+        }
+        log::debug!("setting trigger by writing to {}", path.display());
+        fs::write(&path, name).map_err(|err| PathError::new(err, path))?;
+        self.trigger = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Read the brightness again, which may differ from the last value set if the
+    /// hardware clamped or adjusted it. Returns the new brightness.
+    pub fn refresh(&mut self) -> Result<Brightness, PathError> {
+        let brightness = if self.class == Class::Ddc {
+            crate::ddc::read_device(self.path.clone())?.brightness
+        } else {
+            parse_brightness(&self.path.join("brightness"))?
+        };
+        self.brightness = brightness.min(self.max_brightness);
+        Ok(self.brightness)
+    }
+
+    /// Set the brightness by writing directly to sysfs, without going through D-Bus.
+    /// Values above the max brightness are clamped.
+    pub fn set_brightness_sysfs(&mut self, value: Brightness) -> Result<(), PathError> {
+        let brightness = value.min(self.max_brightness);
+        let path = self.path.join("brightness");
+        log::debug!("setting brightness by writing to {}", path.display());
+        fs::write(&path, brightness.to_string()).map_err(|err| PathError::new(err, path))?;
+        self.brightness = brightness;
+        Ok(())
     }
 }
 
 fn parse_brightness(path: &Path) -> Result<Brightness, PathError> {
-    fs::read_to_string(path)
-        .map_err(|err| PathError::new(err, path))?
-        .trim()
+    let content = fs::read_to_string(path).map_err(|err| PathError::new(err, path))?;
+    let content = content.trim();
+    content
         .parse()
-        .map_err(|err| PathError::new(io::Error::other(err), path))
+        .map_err(|err| invalid_value_error(content, err, path))
 }
 
-#[derive(Debug, Clone, Default)]
+/// Error for a file with unexpected `content`, which is included (truncated if too long)
+/// since drivers may write arbitrary values (e.g. `auto`).
+fn invalid_value_error(content: &str, err: impl Display, path: &Path) -> PathError {
+    const MAX_CHARS: usize = 32;
+    let mut value: String = content.chars().take(MAX_CHARS).collect();
+    if content.chars().nth(MAX_CHARS).is_some() {
+        value.push('…');
+    }
+    let error = io::Error::other(format!("invalid value {value:?} ({err})"));
+    PathError::new(error, path)
+}
+
+/// Read a text attribute of a device (e.g. `type`), if it exists.
+fn read_attribute(path: &Path) -> Result<Option<String>, PathError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content.trim().to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(PathError::new(err, path)),
+    }
+}
+
+/// Parse the content of a `trigger` file, a list of triggers with the active one in
+/// brackets (e.g. `none [kbd-capslock] timer`). Returns all triggers and the active one.
+fn parse_triggers(content: &str) -> (Vec<&str>, Option<&str>) {
+    let mut active = None;
+    let triggers = content
+        .split_whitespace()
+        .map(|trigger| match trigger.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            Some(trigger) => {
+                active = Some(trigger);
+                trigger
+            }
+            None => trigger,
+        })
+        .collect();
+    (triggers, active)
+}
+
+/// Parse the values of a `multi_intensity` file, if it exists.
+fn parse_multi_intensity(path: &Path) -> Result<Option<Vec<Brightness>>, PathError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(PathError::new(err, path)),
+    };
+    content
+        .split_whitespace()
+        .map(|value| value.parse())
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(|err| invalid_value_error(content.trim(), err, path))
+}
+
+/// Part of the name of keyboard backlight LEDs, e.g. `dell::kbd_backlight`.
+pub const KEYBOARD_BACKLIGHT: &str = "kbd_backlight";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DeviceFilters {
-    pub class: Option<Class>,
+    /// Classes to search for devices, all classes if empty.
+    pub classes: Vec<Class>,
     pub device_name: Option<String>,
+    /// Part of the name of devices, unlike `device_name` it doesn't need to be a whole
+    /// path component.
+    pub device_contains: Option<String>,
+    /// Names of devices to leave out, even if they match the other filters.
+    pub exclude: Vec<String>,
+    /// Directory to search for devices instead of [`SYSFS_ROOT`].
+    pub sysfs_root: Option<PathBuf>,
+    /// Resolve symbolic links, so devices are named after the real device.
+    pub follow_symlinks: bool,
+    /// Prefer devices by their `type` when picking a single device.
+    pub prefer_type: bool,
+    /// Leave out devices with a max brightness lower than this.
+    pub min_resolution: Brightness,
+    /// Leave out devices whose brightness can't be written directly.
+    pub only_writable: bool,
+    /// Fail if any device can't be read, instead of skipping it.
+    pub strict: bool,
+}
+
+impl DeviceFilters {
+    pub fn sysfs_root(&self) -> &Path {
+        self.sysfs_root.as_deref().unwrap_or(Path::new(SYSFS_ROOT))
+    }
+
+    /// Whether the device is matched by these filters.
+    pub fn matches(&self, device: &Device) -> bool {
+        (self.classes.is_empty() || self.classes.contains(&device.class))
+            && device.max_brightness >= self.min_resolution
+            && self.matches_path(&device.path)
+    }
+
+    fn matches_path(&self, path: &Path) -> bool {
+        self.device_name
+            .as_ref()
+            .is_none_or(|name| path.ends_with(name))
+            && self.device_contains.as_ref().is_none_or(|part| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().contains(part.as_str()))
+            })
+            && !self.exclude.iter().any(|name| path.ends_with(name))
+    }
 }
 
 impl From<crate::FilterArgs> for DeviceFilters {
     #[inline]
     fn from(filter: crate::FilterArgs) -> Self {
+        (&filter).into()
+    }
+}
+
+impl From<&crate::FilterArgs> for DeviceFilters {
+    #[inline]
+    fn from(filter: &crate::FilterArgs) -> Self {
+        let (classes, device_contains) = if filter.keyboard {
+            (vec![Class::Leds], Some(KEYBOARD_BACKLIGHT.to_string()))
+        } else {
+            let classes = filter.class.iter().flat_map(|class| class.classes());
+            (classes.copied().collect(), filter.device_contains.clone())
+        };
         Self {
-            class: filter.class,
-            device_name: filter.device,
+            classes,
+            device_name: filter.device.clone(),
+            device_contains,
+            exclude: filter.exclude.clone(),
+            sysfs_root: filter.sysfs_root.clone(),
+            follow_symlinks: filter.follow_symlinks,
+            prefer_type: filter.prefer_type,
+            min_resolution: filter.min_resolution,
+            only_writable: filter.only_writable,
+            strict: filter.strict,
         }
     }
 }
 
-fn iter_paths(prefix: &str) -> Result<impl Iterator<Item = PathBuf>, PathError> {
+fn iter_paths(prefix: &Path) -> Result<impl Iterator<Item = PathBuf> + use<>, PathError> {
     Ok(fs::read_dir(prefix)
         .map_err(|err| PathError::new(err, prefix))?
         .filter_map(|entry| entry.inspect_err(|err| log::warn!("{err}")).ok())
@@ -224,38 +897,167 @@ fn iter_paths(prefix: &str) -> Result<impl Iterator<Item = PathBuf>, PathError>
         .filter(|path| path.is_dir()))
 }
 
-fn iter_devices(filters: &DeviceFilters) -> Result<impl Iterator<Item = Device> + '_, PathError> {
-    let mut paths: Vec<PathBuf> = if let Some(class) = filters.class {
-        iter_paths(class.prefix())?.collect()
+/// Paths of the devices in each class directory, so they are only searched once while
+/// running a command. Each command creates its own, since devices may be added or
+/// removed between commands.
+#[derive(Default)]
+pub struct DeviceCache {
+    paths: RefCell<BTreeMap<PathBuf, Vec<PathBuf>>>,
+}
+
+impl DeviceCache {
+    /// Paths of the devices in `prefix`, only calling `search` if they aren't cached.
+    fn paths(
+        &self,
+        prefix: PathBuf,
+        search: impl FnOnce(&Path) -> Result<Vec<PathBuf>, PathError>,
+    ) -> Result<Vec<PathBuf>, PathError> {
+        if let Some(paths) = self.paths.borrow().get(&prefix) {
+            log::debug!("using cached devices for {}", prefix.display());
+            return Ok(paths.clone());
+        }
+        let paths = search(&prefix)?;
+        self.paths.borrow_mut().insert(prefix, paths.clone());
+        Ok(paths)
+    }
+}
+
+/// Names of all devices in every class, including those that can't be read.
+pub fn device_names() -> impl Iterator<Item = String> {
+    [Class::Backlight, Class::Leds]
+        .into_iter()
+        .filter_map(|class| iter_paths(&class.prefix(Path::new(SYSFS_ROOT))).ok())
+        .flatten()
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+}
+
+fn iter_devices<'a>(
+    filters: &'a DeviceFilters,
+    cache: &DeviceCache,
+) -> FetchResult<Box<dyn Iterator<Item = Device> + 'a>> {
+    let classes = if filters.classes.is_empty() {
+        &[Class::Backlight, Class::Leds]
     } else {
-        iter_paths(Class::Backlight.prefix())?
-            .chain(iter_paths(Class::Leds.prefix())?)
-            .collect()
+        filters.classes.as_slice()
     };
+    let mut paths = Vec::new();
+    for (i, class) in classes.iter().enumerate() {
+        // Skip classes given more than once
+        if classes[..i].contains(class) {
+            continue;
+        }
+        let prefix = class.prefix(filters.sysfs_root());
+        paths.extend(cache.paths(prefix, |prefix| match class {
+            Class::Ddc => Ok(crate::ddc::detect()),
+            _ => Ok(iter_paths(prefix)?.collect()),
+        })?);
+    }
+    let devices = devices_from_paths(paths, filters);
+    if !filters.strict {
+        let devices = devices.filter_map(|res| res.inspect_err(|err| log::warn!("{err}")).ok());
+        return Ok(Box::new(devices));
+    }
+    // All devices must be read before any is returned, to know if one failed.
+    let (devices, errors): (Vec<_>, Vec<_>) = devices.partition(Result::is_ok);
+    if !errors.is_empty() {
+        return Err(FetchError::Invalid(errors.into_iter().filter_map(Result::err).collect()));
+    }
+    Ok(Box::new(devices.into_iter().flatten()))
+}
 
+/// Devices at `paths` matching `filters`, or the error reading them.
+fn devices_from_paths(
+    mut paths: Vec<PathBuf>,
+    filters: &DeviceFilters,
+) -> impl Iterator<Item = Result<Device, PathError>> + '_ {
     paths.sort();
 
-    let paths = paths.into_iter().filter_map(|path| {
-        if filters
-            .device_name
-            .as_ref()
-            .is_none_or(|name| path.ends_with(name))
-        {
-            Device::from_path(path)
-                .inspect_err(|err| log::warn!("{err}"))
-                .ok()
-        } else {
-            None
-        }
-    });
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let canonical = if filters.follow_symlinks {
+                fs::canonicalize(&path)
+                    .map_err(|err| PathError::new(err, &path))
+                    .inspect_err(|err| log::warn!("{err}"))
+                    .ok()
+            } else {
+                None
+            };
+            if !filters.matches_path(canonical.as_deref().unwrap_or(&path)) {
+                return None;
+            }
+            // The class is derived from the original path, the real device may be anywhere.
+            let mut device = match Device::read(path, filters.strict) {
+                Ok(device) => device,
+                Err(err) => return Some(Err(err)),
+            };
+            if let Some(canonical) = canonical {
+                device.name = canonical.file_name()?.to_string_lossy().into_owned();
+                device.path = canonical;
+            }
+            Some(Ok(device))
+        })
+        .filter(|res| {
+            let Ok(device) = res else {
+                return true;
+            };
+            // Such devices can't be meaningfully controlled.
+            if device.max_brightness == 0 {
+                log::warn!(r#"skipping device "{}" with max brightness of 0"#, device.name);
+                return false;
+            }
+            if device.max_brightness < filters.min_resolution {
+                return false;
+            }
+            let writable = !filters.only_writable || device.is_writable();
+            if !writable {
+                log::info!(r#"skipping device "{}" that can't be written"#, device.name);
+            }
+            writable
+        })
+}
 
-    Ok(paths)
+/// Order in which devices are listed.
+#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+pub enum SortKey {
+    /// Alphabetical order of device paths
+    #[default]
+    Name,
+    /// Brightness percentage, highest first
+    Brightness,
+    /// Device class
+    Class,
+}
+
+/// Sort devices by `key`, shared by the commands that list devices so they're ordered
+/// the same way.
+pub fn sort_devices(devices: &mut [Device], key: SortKey, reverse: bool) {
+    // Devices are already sorted by path, and stable sorting keeps that order for
+    // equal keys.
+    match key {
+        SortKey::Name => {}
+        SortKey::Brightness => {
+            // Same percentage shown by `info`.
+            let percent = |device: &Device| {
+                crate::Scale::of_class(device.class)
+                    .percent(device.brightness, device.max_brightness)
+                    .get()
+            };
+            devices.sort_by(|a, b| percent(b).total_cmp(&percent(a)));
+        }
+        SortKey::Class => devices.sort_by_key(|device| device.class.to_string()),
+    }
+    if reverse {
+        devices.reverse();
+    }
 }
 
 #[derive(Debug)]
 pub enum FetchError {
     IO(PathError),
-    NotFound(DeviceFilters),
+    NotFound(Box<DeviceFilters>),
+    /// Devices that couldn't be read with [`DeviceFilters::strict`].
+    Invalid(Vec<PathError>),
 }
 
 impl fmt::Display for FetchError {
@@ -265,10 +1067,16 @@ impl fmt::Display for FetchError {
             Self::NotFound(filters) => {
                 if let Some(name) = &filters.device_name {
                     write!(f, r#"device with name "{name}" not found"#)
+                } else if let Some(part) = &filters.device_contains {
+                    write!(f, r#"device with name containing "{part}" not found"#)
                 } else {
                     f.write_str("no devices found")
                 }
             }
+            Self::Invalid(errors) => {
+                write!(f, "failed to read {} device(s)", errors.len())?;
+                errors.iter().try_for_each(|error| write!(f, "\n  {error}"))
+            }
         }
     }
 }
@@ -284,19 +1092,593 @@ impl From<PathError> for FetchError {
 type FetchResult<T> = Result<T, FetchError>;
 
 /// Returns all devices matching the given filters.
-pub fn get_devices(filters: &DeviceFilters) -> FetchResult<impl Iterator<Item = Device> + '_> {
-    let mut iter = iter_devices(filters)?.peekable();
+pub fn get_devices<'a>(
+    filters: &'a DeviceFilters,
+    cache: &DeviceCache,
+) -> FetchResult<impl Iterator<Item = Device> + use<'a>> {
+    let mut iter = iter_devices(filters, cache)?.peekable();
     if iter.peek().is_some() {
         Ok(iter)
     } else {
-        Err(FetchError::NotFound(filters.clone()))
+        Err(FetchError::NotFound(Box::new(filters.clone())))
     }
 }
 
 /// Returns the first encountered device matching the given filters.
-/// Which device is "first" is determined by alphabetical order.
-pub fn get_device(filters: &DeviceFilters) -> FetchResult<Device> {
-    iter_devices(filters)?
-        .next()
-        .ok_or_else(|| FetchError::NotFound(filters.clone()))
+/// Which device is "first" is determined by alphabetical order, or with `prefer_type`
+/// by [`type_preference`]. When a device name is given without classes, devices of the
+/// class [`likely_class`] infers from the name come first.
+pub fn get_device(filters: &DeviceFilters, cache: &DeviceCache) -> FetchResult<Device> {
+    let likely_class = filters
+        .device_name
+        .as_deref()
+        .filter(|_| filters.classes.is_empty())
+        .and_then(likely_class);
+    let mut devices = iter_devices(filters, cache)?;
+    let device = if filters.prefer_type || likely_class.is_some() {
+        devices.min_by_key(|device| {
+            let other_class = likely_class.is_some_and(|class| device.class != class);
+            let preference = if filters.prefer_type {
+                type_preference(device)
+            } else {
+                0
+            };
+            (other_class, preference)
+        })
+    } else {
+        devices.next()
+    };
+    device.ok_or_else(|| FetchError::NotFound(Box::new(filters.clone())))
+}
+
+/// Interval between searches in [`wait_for_device`].
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Like [`get_device`], but search again until a device is found or `timeout` elapses,
+/// for devices whose driver is loaded late (e.g. during boot).
+pub fn wait_for_device(filters: &DeviceFilters, timeout: Duration) -> FetchResult<Device> {
+    let start = Instant::now();
+    loop {
+        // Search from scratch each time, the device may have been added since.
+        match get_device(filters, &DeviceCache::default()) {
+            Err(err) if start.elapsed() < timeout => {
+                log::debug!("waiting for device: {err}");
+                thread::sleep(WAIT_INTERVAL);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Guess the class of a device from its name, to choose between devices with the same
+/// name in different classes. LED names have the form `devicename:color:function`,
+/// other names containing `backlight` are assumed to be backlights.
+fn likely_class(name: &str) -> Option<Class> {
+    if name.contains(':') {
+        Some(Class::Leds)
+    } else if name.contains("backlight") {
+        Some(Class::Backlight)
+    } else {
+        None
+    }
+}
+
+/// Order of preference of a device by its type, lower is better. Raw backlights control
+/// the hardware directly, while firmware ones are often broken or coarse.
+fn type_preference(device: &Device) -> u8 {
+    match device.device_type.as_deref() {
+        Some("raw") => 0,
+        Some("platform") => 1,
+        Some("firmware") => 2,
+        _ => 3,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Directory for the files of a test, removed when dropped.
+    pub(crate) struct MockRoot(PathBuf);
+
+    impl std::ops::Deref for MockRoot {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for MockRoot {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for MockRoot {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(crate) fn mock_root(name: &str) -> MockRoot {
+        MockRoot(std::env::temp_dir().join(format!(
+            "{}-test-{}-{name}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        )))
+    }
+
+    /// Create a fake device directory in `root` with the given brightness values.
+    pub(crate) fn mock_device(
+        root: &Path,
+        name: &str,
+        brightness: Brightness,
+        max_brightness: Brightness,
+    ) -> PathBuf {
+        write_mock_device(root.join(name), brightness, max_brightness)
+    }
+
+    /// Create a fake device of `class` in a fake sysfs tree at `root`.
+    pub(crate) fn mock_class_device(
+        root: &Path,
+        class: Class,
+        name: &str,
+        brightness: Brightness,
+        max_brightness: Brightness,
+    ) -> PathBuf {
+        write_mock_device(class.prefix(root).join(name), brightness, max_brightness)
+    }
+
+    /// Names of the devices in `paths` that pass `filters`.
+    fn device_names(paths: Vec<PathBuf>, filters: &DeviceFilters) -> Vec<String> {
+        devices_from_paths(paths, filters)
+            .flatten()
+            .map(|device| device.name)
+            .collect()
+    }
+
+    fn write_mock_device(
+        path: PathBuf,
+        brightness: Brightness,
+        max_brightness: Brightness,
+    ) -> PathBuf {
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("brightness"), brightness.to_string()).unwrap();
+        fs::write(path.join("max_brightness"), max_brightness.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_class_from_str() {
+        for class in [Class::Leds, Class::Backlight, Class::Ddc] {
+            assert_eq!(class.to_string().parse(), Ok(class));
+        }
+        let err = "Leds".parse::<Class>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"unknown device class "Leds", expected leds, backlight or ddc"#
+        );
+    }
+
+    #[test]
+    fn test_transient_dbus_errors() {
+        use zbus::fdo;
+
+        let io_error = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(controller::is_transient(&zbus::Error::InputOutput(io_error.into())));
+        let fdo_error = |err| zbus::Error::FDO(Box::new(err));
+        let no_reply = fdo::Error::NoReply(String::new());
+        assert!(controller::is_transient(&fdo_error(no_reply)));
+        let access_denied = fdo::Error::AccessDenied(String::new());
+        assert!(!controller::is_transient(&fdo_error(access_denied)));
+        let unknown_method = fdo::Error::UnknownMethod(String::new());
+        assert!(!controller::is_transient(&fdo_error(unknown_method)));
+    }
+
+    #[test]
+    fn test_unreadable_brightness() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = mock_root("unreadable_brightness");
+        let path = mock_device(&root, "unreadable", 5, 10);
+        let brightness = path.join("brightness");
+        fs::set_permissions(&brightness, fs::Permissions::from_mode(0o200)).unwrap();
+        // Permissions don't apply to root
+        let expected = if fs::read(&brightness).is_ok() { 5 } else { 0 };
+        let device = Device::from_path(&path).unwrap();
+        assert_eq!(device.brightness, expected);
+        assert_eq!(device.max_brightness, 10);
+
+        // Other errors aren't ignored
+        fs::remove_file(&brightness).unwrap();
+        assert!(Device::from_path(&path).is_err());
+        fs::write(&brightness, "auto").unwrap();
+        assert!(Device::from_path(&path).is_err());
+        fs::remove_file(path.join("max_brightness")).unwrap();
+        assert!(Device::from_path(&path).is_err());
+    }
+
+    #[test]
+    fn test_zero_max_brightness_is_skipped() {
+        let root = mock_root("zero_max_brightness_is_skipped");
+        let paths = vec![
+            mock_device(&root, "zero_max", 0, 0),
+            mock_device(&root, "valid", 5, 10),
+        ];
+        let filters = DeviceFilters::default();
+        let devices: Vec<_> = devices_from_paths(paths, &filters).flatten().collect();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "valid");
+    }
+
+    #[test]
+    fn test_strict() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let cache = DeviceCache::default();
+        let root = mock_root("strict");
+        mock_class_device(&root, Class::Backlight, "working", 1, 10);
+        let broken = mock_class_device(&root, Class::Backlight, "broken", 1, 10);
+        fs::remove_file(broken.join("max_brightness")).unwrap();
+        // Assumed to be at 0 unless strict
+        let write_only = mock_class_device(&root, Class::Backlight, "write_only", 1, 10);
+        let brightness = write_only.join("brightness");
+        fs::set_permissions(&brightness, fs::Permissions::from_mode(0o200)).unwrap();
+        // Permissions don't apply to root
+        let unreadable = usize::from(fs::read(&brightness).is_err());
+        let mut filters = DeviceFilters {
+            classes: vec![Class::Backlight],
+            sysfs_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(get_devices(&filters, &cache).unwrap().count(), 2);
+        filters.strict = true;
+        let result = get_devices(&filters, &cache);
+        assert!(
+            matches!(result, Err(FetchError::Invalid(errors)) if errors.len() == 1 + unreadable)
+        );
+    }
+
+    #[test]
+    fn test_invalid_brightness() {
+        let root = mock_root("invalid_brightness");
+        let path = mock_device(&root, "invalid", 0, 10);
+        fs::write(path.join("brightness"), "auto\n").unwrap();
+        let err = parse_brightness(&path.join("brightness"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(r#"invalid value "auto" ("#), "{err}");
+
+        fs::write(path.join("brightness"), "x".repeat(40)).unwrap();
+        let err = parse_brightness(&path.join("brightness"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&format!("\"{}…\"", "x".repeat(32))), "{err}");
+    }
+
+    #[test]
+    fn test_actual_brightness() {
+        let root = mock_root("actual_brightness");
+        let path = mock_device(&root, "actual", 5, 10);
+        fs::write(path.join("actual_brightness"), "3").unwrap();
+        let mut device = Device::from_path(path).unwrap();
+        assert_eq!(device.brightness, 5);
+        device.read_actual_brightness().unwrap();
+        assert_eq!(device.brightness, 3);
+
+        let mut device = Device::from_path(mock_device(&root, "no_actual", 5, 10)).unwrap();
+        device.read_actual_brightness().unwrap();
+        assert_eq!(device.brightness, 5);
+    }
+
+    #[test]
+    fn test_device_type() {
+        let root = mock_root("device_type");
+        let path = mock_device(&root, "type", 5, 10);
+        assert_eq!(Device::from_path(&path).unwrap().device_type, None);
+        fs::write(path.join("type"), "firmware\n").unwrap();
+        fs::write(path.join("scale"), "non-linear\n").unwrap();
+        let device = Device::from_path(&path).unwrap();
+        assert_eq!(device.device_type.as_deref(), Some("firmware"));
+        assert_eq!(device.scale.as_deref(), Some("non-linear"));
+        assert_eq!(device.levels(), 11);
+        assert_eq!(device.trigger, None);
+    }
+
+    #[test]
+    fn test_triggers() {
+        let root = mock_root("triggers");
+        assert_eq!(
+            parse_triggers("none kbd-scrolllock [kbd-capslock] timer\n"),
+            (vec!["none", "kbd-scrolllock", "kbd-capslock", "timer"], Some("kbd-capslock"))
+        );
+        assert_eq!(parse_triggers("none timer"), (vec!["none", "timer"], None));
+
+        let path = mock_device(&root, "trigger", 1, 1);
+        let mut device = Device::from_path(&path).unwrap();
+        assert!(device.set_trigger("none").is_err());
+        fs::write(path.join("trigger"), "[none] timer").unwrap();
+        let mut device = Device::from_path(&path).unwrap();
+        assert_eq!(device.trigger.as_deref(), Some("none"));
+        device.set_trigger("timer").unwrap();
+        assert_eq!(device.trigger.as_deref(), Some("timer"));
+        assert_eq!(fs::read_to_string(path.join("trigger")).unwrap(), "timer");
+        assert!(device.set_trigger("heartbeat").is_err());
+    }
+
+    #[test]
+    fn test_set_brightness_sysfs() {
+        let root = mock_root("set_brightness_sysfs");
+        let path = mock_device(&root, "set_sysfs", 5, 10);
+        let mut device = Device::from_path(&path).unwrap();
+        device.set_brightness_sysfs(7).unwrap();
+        assert_eq!(device.brightness, 7);
+        device.set_brightness_sysfs(20).unwrap();
+        assert_eq!(device.brightness, 10);
+        assert_eq!(fs::read_to_string(path.join("brightness")).unwrap(), "10");
+
+        // The controller writes the clamped value too
+        let options = ControllerOptions {
+            method: WriteMethod::Sysfs,
+            ..Default::default()
+        };
+        let mut device = Device::from_path(&path).unwrap();
+        Controller::new(options)
+            .unwrap()
+            .set_brightness(&mut device, 30)
+            .unwrap();
+        assert_eq!(device.brightness, 10);
+    }
+
+    #[test]
+    fn test_refresh() {
+        let root = mock_root("refresh");
+        let path = mock_device(&root, "refresh", 5, 10);
+        let mut device = Device::from_path(&path).unwrap();
+        fs::write(path.join("brightness"), "3").unwrap();
+        assert_eq!(device.refresh().unwrap(), 3);
+        assert_eq!(device.brightness, 3);
+        fs::remove_file(path.join("brightness")).unwrap();
+        assert!(device.refresh().is_err());
+    }
+
+    #[test]
+    fn test_sysfs_root() {
+        let cache = DeviceCache::default();
+        let root = mock_root("sysfs");
+        mock_class_device(&root, Class::Backlight, "panel", 1, 2);
+        mock_class_device(&root, Class::Leds, "capslock", 1, 2);
+
+        let filters = DeviceFilters {
+            sysfs_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let devices: Vec<_> = get_devices(&filters, &cache).unwrap().collect();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "panel");
+        assert!(matches!(devices[0].class, Class::Backlight));
+        assert_eq!(devices[1].name, "capslock");
+        assert!(matches!(devices[1].class, Class::Leds));
+    }
+
+    #[test]
+    fn test_wait_for_device() {
+        let root = mock_root("wait");
+        fs::create_dir_all(Class::Backlight.prefix(&root)).unwrap();
+        let filters = DeviceFilters {
+            classes: vec![Class::Backlight],
+            device_name: Some("late".to_string()),
+            sysfs_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        assert!(wait_for_device(&filters, Duration::ZERO).is_err());
+
+        let driver = {
+            let root = root.to_path_buf();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(150));
+                mock_class_device(&root, Class::Backlight, "late", 1, 2);
+            })
+        };
+        let device = wait_for_device(&filters, Duration::from_secs(5)).unwrap();
+        assert_eq!(device.name, "late");
+        driver.join().unwrap();
+    }
+
+    #[test]
+    fn test_follow_symlinks() {
+        let root = mock_root("follow_symlinks");
+        let real = mock_device(&root, "real_device", 1, 10);
+        let link = real.with_file_name("link_device");
+        _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let names = |filters: &DeviceFilters| device_names(vec![link.clone()], filters);
+        let mut filters = DeviceFilters::default();
+        assert_eq!(names(&filters), ["link_device"]);
+        filters.follow_symlinks = true;
+        assert_eq!(names(&filters), ["real_device"]);
+        filters.device_name = Some("link_device".to_string());
+        assert!(names(&filters).is_empty());
+    }
+
+    #[test]
+    fn test_device_cache() {
+        let root = mock_root("cache");
+        let prefix = Class::Leds.prefix(&root);
+        fs::create_dir_all(prefix.join("first")).unwrap();
+        let search = |prefix: &Path| Ok(iter_paths(prefix)?.collect());
+
+        let cache = DeviceCache::default();
+        assert_eq!(cache.paths(prefix.clone(), search).unwrap().len(), 1);
+        fs::create_dir_all(prefix.join("second")).unwrap();
+        assert_eq!(cache.paths(prefix.clone(), search).unwrap().len(), 1);
+        // Another command searches again
+        let cache = DeviceCache::default();
+        assert_eq!(cache.paths(prefix, search).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_sort_devices() {
+        let root = mock_root("sort_devices");
+        let paths = vec![
+            mock_device(&root, "sort_a", 1, 10),
+            mock_device(&root, "sort_b", 9, 10),
+            mock_device(&root, "sort_c", 5, 10),
+        ];
+        let mut devices: Vec<_> = devices_from_paths(paths, &DeviceFilters::default())
+            .flatten()
+            .collect();
+        let names = |devices: &[Device]| -> Vec<_> {
+            devices.iter().map(|device| device.name.clone()).collect()
+        };
+        sort_devices(&mut devices, SortKey::Name, true);
+        assert_eq!(names(&devices), ["sort_c", "sort_b", "sort_a"]);
+        sort_devices(&mut devices, SortKey::Brightness, false);
+        assert_eq!(names(&devices), ["sort_b", "sort_c", "sort_a"]);
+        sort_devices(&mut devices, SortKey::Brightness, true);
+        assert_eq!(names(&devices), ["sort_a", "sort_c", "sort_b"]);
+
+        // LEDs use a linear scale, so 30/100 is below a backlight at 5/10.
+        let paths = vec![
+            mock_class_device(&root, Class::Leds, "sort_led", 30, 100),
+            mock_class_device(&root, Class::Backlight, "sort_backlight", 5, 10),
+        ];
+        let mut devices: Vec<_> = devices_from_paths(paths, &DeviceFilters::default())
+            .flatten()
+            .collect();
+        sort_devices(&mut devices, SortKey::Brightness, false);
+        assert_eq!(names(&devices), ["sort_backlight", "sort_led"]);
+    }
+
+    #[test]
+    fn test_prefer_type() {
+        let cache = DeviceCache::default();
+        let root = mock_root("prefer_type");
+        for (name, device_type) in [("acpi_video0", "firmware"), ("intel_backlight", "raw")] {
+            let path = mock_class_device(&root, Class::Backlight, name, 1, 2);
+            fs::write(path.join("type"), device_type).unwrap();
+        }
+
+        let mut filters = DeviceFilters {
+            classes: vec![Class::Backlight],
+            sysfs_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(get_device(&filters, &cache).unwrap().name, "acpi_video0");
+        filters.prefer_type = true;
+        assert_eq!(get_device(&filters, &cache).unwrap().name, "intel_backlight");
+    }
+
+    #[test]
+    fn test_likely_class() {
+        let cache = DeviceCache::default();
+        let root = mock_root("likely_class");
+        for class in [Class::Backlight, Class::Leds] {
+            for name in ["kbd_backlight", "dell::kbd_backlight"] {
+                mock_class_device(&root, class, name, 1, 2);
+            }
+        }
+
+        let mut filters = DeviceFilters {
+            device_name: Some("dell::kbd_backlight".to_string()),
+            sysfs_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(get_device(&filters, &cache).unwrap().class, Class::Leds);
+        filters.device_name = Some("kbd_backlight".to_string());
+        assert_eq!(get_device(&filters, &cache).unwrap().class, Class::Backlight);
+        filters.classes = vec![Class::Leds];
+        assert_eq!(get_device(&filters, &cache).unwrap().class, Class::Leds);
+        assert_eq!(likely_class("acpi_video0"), None);
+    }
+
+    #[test]
+    fn test_filters_serde() {
+        let filters = DeviceFilters {
+            classes: vec![Class::Leds, Class::Ddc],
+            device_contains: Some(KEYBOARD_BACKLIGHT.to_string()),
+            exclude: vec!["input2::capslock".to_string()],
+            min_resolution: 2,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&filters).unwrap();
+        assert_eq!(serde_json::from_str::<DeviceFilters>(&json).unwrap(), filters);
+
+        // Missing fields take their default value.
+        let filters: DeviceFilters = serde_json::from_str(r#"{"classes": ["backlight"]}"#).unwrap();
+        assert_eq!(filters.classes, [Class::Backlight]);
+        assert_eq!(filters.device_name, None);
+    }
+
+    #[test]
+    fn test_exclude_filter() {
+        let root = mock_root("exclude_filter");
+        let paths = vec![
+            mock_device(&root, "exclude_a", 1, 10),
+            mock_device(&root, "exclude_b", 1, 10),
+            mock_device(&root, "exclude_c", 1, 10),
+        ];
+        let filters = DeviceFilters {
+            exclude: vec!["exclude_a".to_string(), "exclude_c".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(device_names(paths, &filters), ["exclude_b"]);
+
+        let filters = DeviceFilters {
+            device_contains: Some("ude_".to_string()),
+            exclude: vec!["exclude_b".to_string()],
+            ..Default::default()
+        };
+        let paths = vec![
+            mock_device(&root, "exclude_a", 1, 10),
+            mock_device(&root, "exclude_b", 1, 10),
+            mock_device(&root, "include", 1, 10),
+        ];
+        assert_eq!(device_names(paths, &filters), ["exclude_a"]);
+    }
+
+    #[test]
+    fn test_only_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = mock_root("only_writable");
+        let paths = vec![
+            mock_device(&root, "writable_a", 1, 10),
+            mock_device(&root, "writable_b", 1, 10),
+        ];
+        let brightness = paths[0].join("brightness");
+        fs::set_permissions(&brightness, fs::Permissions::from_mode(0o444)).unwrap();
+        // Permissions don't apply to root
+        let writable = fs::OpenOptions::new().write(true).open(&brightness).is_ok();
+        let read_only = Device::from_path(&paths[0]).unwrap();
+        assert_eq!(read_only.is_writable(), writable);
+        assert!(Device::from_path(&paths[1]).unwrap().is_writable());
+
+        let mut filters = DeviceFilters::default();
+        assert_eq!(device_names(paths.clone(), &filters), ["writable_a", "writable_b"]);
+        filters.only_writable = true;
+        let expected: &[&str] = if writable {
+            &["writable_a", "writable_b"]
+        } else {
+            &["writable_b"]
+        };
+        assert_eq!(device_names(paths, &filters), expected);
+    }
+
+    #[test]
+    fn test_min_resolution() {
+        let root = mock_root("min_resolution");
+        let paths = vec![
+            mock_device(&root, "resolution_a", 1, 1),
+            mock_device(&root, "resolution_b", 1, 2),
+        ];
+        let mut filters = DeviceFilters::default();
+        assert_eq!(device_names(paths.clone(), &filters), ["resolution_a", "resolution_b"]);
+        filters.min_resolution = 2;
+        assert_eq!(device_names(paths.clone(), &filters), ["resolution_b"]);
+        filters.min_resolution = 3;
+        assert!(device_names(paths, &filters).is_empty());
+    }
 }
@@ -0,0 +1,158 @@
+//! Lookup tables mapping percentages to raw brightness values, for displays whose
+//! response doesn't follow the default logarithmic curve.
+//!
+//! Tables are read from a CSV file with rows of `percent,raw`, or from a JSON file (when
+//! the extension is `.json`) with an array of `[percent, raw]` pairs. Values between
+//! entries are interpolated linearly.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::device::{Brightness, PathError};
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathError),
+    Parse { path: PathBuf, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IO(error) => error.fmt(f),
+            Self::Parse { path, message } => {
+                write!(f, "invalid curve file \"{}\": {message}", path.display())
+            }
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+#[derive(Debug, PartialEq)]
+pub struct LookupTable {
+    /// Pairs of percentage and raw brightness, both increasing.
+    points: Vec<(f32, Brightness)>,
+}
+
+impl LookupTable {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        log::info!("reading curve from {}", path.display());
+        let content =
+            fs::read_to_string(path).map_err(|err| Error::IO(PathError::new(err, path)))?;
+        let points = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&content).map_err(|err| err.to_string())
+        } else {
+            parse_csv(&content)
+        };
+        points.and_then(Self::new).map_err(|message| Error::Parse {
+            path: path.to_path_buf(),
+            message,
+        })
+    }
+
+    /// Create a table from pairs of percentage and raw brightness, which must be
+    /// increasing.
+    pub fn new(points: Vec<(f32, Brightness)>) -> Result<Self, String> {
+        if points.len() < 2 {
+            return Err("expected at least 2 entries".to_string());
+        }
+        if let Some((percent, _)) = points
+            .iter()
+            .find(|(percent, _)| !(0.0..=100.0).contains(percent))
+        {
+            return Err(format!("{percent} is not a percentage between 0 and 100"));
+        }
+        if let Some(pair) = points
+            .windows(2)
+            .find(|pair| pair[0].0 >= pair[1].0 || pair[0].1 > pair[1].1)
+        {
+            return Err(format!(
+                "entries must be increasing, found {:?} before {:?}",
+                pair[0], pair[1]
+            ));
+        }
+        Ok(Self { points })
+    }
+
+    /// Raw brightness for `percent`, clamped to the first and last entries.
+    pub fn brightness(&self, percent: f32) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if percent <= first.0 {
+            return first.1 as f32;
+        }
+        if percent >= last.0 {
+            return last.1 as f32;
+        }
+        let i = self.points.partition_point(|&(p, _)| p <= percent);
+        let ((p0, b0), (p1, b1)) = (self.points[i - 1], self.points[i]);
+        b0 as f32 + (percent - p0) / (p1 - p0) * (b1 as f32 - b0 as f32)
+    }
+
+    /// Inverse of `brightness`, the percentage that maps to `brightness`.
+    pub fn percent(&self, brightness: Brightness) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if brightness <= first.1 {
+            return first.0;
+        }
+        if brightness >= last.1 {
+            return last.0;
+        }
+        let i = self.points.partition_point(|&(_, b)| b <= brightness);
+        let ((p0, b0), (p1, b1)) = (self.points[i - 1], self.points[i]);
+        p0 + (brightness - b0) as f32 / (b1 - b0) as f32 * (p1 - p0)
+    }
+}
+
+/// Parse rows of `percent,raw`, an optional header row is skipped.
+fn parse_csv(content: &str) -> Result<Vec<(f32, Brightness)>, String> {
+    let mut rows = content
+        .lines()
+        .map(str::trim)
+        .filter(|row| !row.is_empty())
+        .peekable();
+    if rows
+        .peek()
+        .is_some_and(|row| row.starts_with(|c: char| c.is_alphabetic()))
+    {
+        rows.next();
+    }
+    rows.map(|row| {
+        let (percent, raw) = row
+            .split_once(',')
+            .ok_or_else(|| format!("invalid row {row:?}: expected percent and raw value"))?;
+        let percent = percent
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid row {row:?}: {err}"))?;
+        let raw = raw
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid row {row:?}: {err}"))?;
+        Ok((percent, raw))
+    })
+    .collect()
+}
+
+#[test]
+fn test_lookup_table() {
+    let table =
+        LookupTable::new(parse_csv("percent,raw\n0,1\n50,100\n\n100,1000\n").unwrap()).unwrap();
+    assert_eq!(table.brightness(0.0), 1.0);
+    assert_eq!(table.brightness(25.0), 50.5);
+    assert_eq!(table.brightness(75.0), 550.0);
+    assert_eq!(table.brightness(100.0), 1000.0);
+    assert_eq!(table.percent(550), 75.0);
+    assert_eq!(table.percent(0), 0.0);
+
+    let table = LookupTable::new(vec![(10.0, 5), (90.0, 10)]).unwrap();
+    assert_eq!(table.brightness(5.0), 5.0);
+    assert_eq!(table.brightness(95.0), 10.0);
+
+    assert!(parse_csv("0;1").is_err());
+    assert!(LookupTable::new(vec![(0.0, 1)]).is_err());
+    assert!(LookupTable::new(vec![(0.0, 1), (101.0, 2)]).is_err());
+    assert!(LookupTable::new(vec![(50.0, 1), (10.0, 2)]).is_err());
+    assert!(LookupTable::new(vec![(0.0, 10), (100.0, 2)]).is_err());
+}
@@ -1,17 +1,34 @@
 use std::env;
 use std::error::Error;
 use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::device::{Brightness, Class, Device};
+use crate::config::Config;
+use crate::device::{
+    Brightness, BrightnessBackend, Class, ControllerOptions, Device, DeviceCache, LazyController,
+    SortKey, WriteMethod,
+};
+use crate::lut::LookupTable;
 use crate::percent::Percent;
 
+mod config;
+mod daemon;
+mod ddc;
 mod device;
+mod lut;
+mod solar;
+mod tui;
 
 mod colors {
     pub use anstyle::Reset;
@@ -35,12 +52,39 @@ mod colors {
 mod logger {
     use std::io::Write;
 
+    use clap::ValueEnum;
     use log::{Level, Metadata, Record};
+    use serde::Serialize;
 
     use crate::BIN_NAME;
     use crate::colors::{self, CYAN, GREEN, MAGENTA, RED, Reset, YELLOW};
 
-    pub struct Logger;
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+    pub enum LogFormat {
+        /// Colored messages for humans
+        #[default]
+        Plain,
+        /// A JSON object per line, with `level` and `msg` fields
+        Json,
+    }
+
+    pub struct Logger {
+        pub format: LogFormat,
+    }
+
+    #[derive(Serialize)]
+    struct JsonRecord<'a> {
+        level: &'a str,
+        msg: String,
+    }
+
+    fn json_line(record: &Record) -> String {
+        let record = JsonRecord {
+            level: &record.level().as_str().to_lowercase(),
+            msg: record.args().to_string(),
+        };
+        serde_json::to_string(&record).expect("serializing a log record")
+    }
 
     impl log::Log for Logger {
         fn enabled(&self, metadata: &Metadata) -> bool {
@@ -48,7 +92,13 @@ mod logger {
         }
 
         fn log(&self, record: &Record) {
-            if self.enabled(record.metadata()) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            if self.format == LogFormat::Json {
+                // Not styled, so it's written directly to stderr.
+                _ = writeln!(std::io::stderr(), "{}", json_line(record));
+            } else {
                 let (severity, color) = match record.level() {
                     Level::Error => ("error", RED),
                     Level::Warn => ("warning", YELLOW),
@@ -71,14 +121,29 @@ mod logger {
 
         fn flush(&self) {}
     }
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(
+            &Record::builder()
+                .level(Level::Warn)
+                .args(format_args!("device \"{}\" not found", "acpi_video0"))
+                .build(),
+        );
+        assert_eq!(line, r#"{"level":"warn","msg":"device \"acpi_video0\" not found"}"#);
+    }
 }
 
 mod percent {
     use core::ops::{Add, Sub};
     use std::fmt;
 
-    #[derive(Debug, Clone, Copy, PartialEq)]
-    pub struct Percent(f32);
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Deserializer, Serialize, de};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+    #[serde(transparent)]
+    pub struct Percent(#[schemars(range(min = 0, max = 100))] f32);
 
     impl Percent {
         pub const MIN: Self = Self::new(0.0).unwrap();
@@ -92,6 +157,11 @@ mod percent {
             }
         }
 
+        /// Create a percentage from a ratio in the range [0, 1].
+        pub const fn from_ratio(ratio: f32) -> Option<Self> {
+            Self::new(ratio * 100.0)
+        }
+
         pub const fn get(self) -> f32 {
             self.0
         }
@@ -103,6 +173,14 @@ mod percent {
         }
     }
 
+    impl<'de> Deserialize<'de> for Percent {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let percent = f32::deserialize(deserializer)?;
+            Self::new(percent)
+                .ok_or_else(|| de::Error::custom("not a percentage between 0 and 100"))
+        }
+    }
+
     impl Add for Percent {
         type Output = Self;
 
@@ -139,10 +217,24 @@ mod percent {
         assert_eq!(Percent::new(f32::INFINITY), None);
         assert_eq!(Percent::new(f32::NEG_INFINITY), None);
     }
+
+    #[test]
+    fn test_percent_from_ratio() {
+        assert_eq!(Percent::from_ratio(0.0), Some(Percent::MIN));
+        assert_eq!(Percent::from_ratio(0.25), Some(Percent(25.0)));
+        assert_eq!(Percent::from_ratio(1.0), Some(Percent::MAX));
+        assert_eq!(Percent::from_ratio(-0.1), None);
+        assert_eq!(Percent::from_ratio(1.1), None);
+        assert_eq!(Percent::from_ratio(f32::NAN), None);
+    }
 }
 
 const BIN_NAME: &str = env!("CARGO_BIN_NAME");
 
+/// Environment variable used as the percent for `add`/`sub` when none is given.
+const DEFAULT_STEP_ENV: &str = "LIGHTER_DEFAULT_STEP";
+const DEFAULT_STEP: Percent = Percent::new(5.0).unwrap();
+
 // Formulas for calculating the perceived percentage of a given value:
 //
 // # value to percent
@@ -151,17 +243,123 @@ const BIN_NAME: &str = env!("CARGO_BIN_NAME");
 // # percent to value
 // value = 10 ^ (percent * log10(max_value) / 100)
 
+/// Strategy for rounding a computed brightness to a raw value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Rounding {
+    /// Round to the nearest value
+    Nearest,
+    /// Round towards the maximum brightness
+    Up,
+    /// Round towards zero
+    Down,
+}
+
+impl Rounding {
+    fn apply(self, value: f32) -> f32 {
+        match self {
+            Self::Nearest => value.round(),
+            Self::Up => value.ceil(),
+            Self::Down => value.floor(),
+        }
+    }
+}
+
+/// Adjustments to the mapping between percentages and brightness values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Curve<'a> {
+    /// Percentage of the max brightness that 100% maps to.
+    pub ceiling: Percent,
+    /// Exponent applied to the percentage (normalized to [0, 1]) before mapping it.
+    /// Values above 1 make low percentages darker, and values below 1 make them brighter.
+    pub gamma: f32,
+    /// Table used to map percentages instead of the logarithmic formula.
+    pub table: Option<&'a LookupTable>,
+    /// Mapping used without a table.
+    pub scale: Scale,
+}
+
+impl Default for Curve<'_> {
+    fn default() -> Self {
+        Self {
+            ceiling: Percent::MAX,
+            gamma: 1.0,
+            table: None,
+            scale: Scale::Perceptual,
+        }
+    }
+}
+
+impl Curve<'_> {
+    /// Inverse of the adjustments, the percentage that gives `brightness` with this curve.
+    fn percent(self, brightness: Brightness, max_brightness: Brightness) -> Percent {
+        let brightness = if self.ceiling == Percent::MAX {
+            brightness
+        } else {
+            let unscaled = (brightness as f32 * 100.0 / self.ceiling.get()).round();
+            (unscaled as Brightness).min(max_brightness)
+        };
+        let percent = match self.table {
+            Some(table) => Percent::new(table.percent(brightness))
+                .expect("table percentages to be between 0 and 100"),
+            None => self.scale.percent(brightness, max_brightness),
+        };
+        if self.gamma == 1.0 {
+            return percent;
+        }
+        Percent::from_ratio((percent.get() / 100.0).powf(self.gamma.recip()))
+            .expect("ratio to stay between 0 and 1")
+    }
+}
+
+fn gamma_parser(s: &str) -> Result<f32, String> {
+    let gamma = s.parse::<f32>().map_err(|_| "not a number".to_string())?;
+    if gamma.is_finite() && gamma > 0.0 {
+        Ok(gamma)
+    } else {
+        Err("not a number greater than 0".to_string())
+    }
+}
+
 /// Convert to a brightness value relative to a maximum brightness.
 /// The conversion adjusts the value in accordance to [human perception][perception].
 ///
+/// The percentage is first raised to the `curve` gamma, and the result is scaled by its
+/// ceiling, so that 100% maps to `ceiling`% of `max_brightness`. If the curve has a
+/// lookup table, it's used instead of the logarithmic formula, and with a linear scale
+/// the percentage is a plain ratio of `max_brightness`.
+///
 /// [perception]: https://konradstrack.ninja/blog/changing-screen-brightness-in-accordance-with-human-perception/
-pub fn brightness_from_percent(percent: &Percent, max_brightness: Brightness) -> Brightness {
+pub fn brightness_from_percent(
+    percent: &Percent,
+    max_brightness: Brightness,
+    rounding: Rounding,
+    curve: Curve<'_>,
+) -> Brightness {
     let percent = percent.get();
-    if percent == 0.0 || max_brightness == 0 {
+    if max_brightness == 0 {
         return 0;
     }
-    let exp = (percent / 100.0) * f32::from(max_brightness).log10();
-    (10_f32).powf(exp).round() as Brightness // Float to integer is a saturated cast
+    let value = if let Some(table) = curve.table {
+        table.brightness(100.0 * (percent / 100.0).powf(curve.gamma))
+    } else if percent == 0.0 {
+        return 0;
+    } else if percent == 100.0 {
+        max_brightness as f32
+    } else {
+        let ratio = (percent / 100.0).powf(curve.gamma);
+        match curve.scale {
+            Scale::Perceptual => (10_f32).powf(ratio * (max_brightness as f32).log10()),
+            Scale::Linear => ratio * max_brightness as f32,
+        }
+    };
+    let value = if curve.ceiling == Percent::MAX {
+        value
+    } else {
+        value * curve.ceiling.get() / 100.0
+    };
+    // Float to integer is a saturated cast
+    let brightness = rounding.apply(value) as Brightness;
+    brightness.min(max_brightness)
 }
 
 /// Inverse of `brightness_from_percent`.
@@ -169,6 +367,9 @@ pub fn brightness_to_percent(brightness: Brightness, max_brightness: Brightness)
     if brightness == 0 {
         return Percent::MIN;
     }
+    if brightness == max_brightness {
+        return Percent::MAX;
+    }
     if max_brightness <= 1 {
         return if brightness < max_brightness {
             Percent::MIN
@@ -176,41 +377,694 @@ pub fn brightness_to_percent(brightness: Brightness, max_brightness: Brightness)
             Percent::MAX
         };
     }
-    let percent = f32::from(brightness).log(f32::from(max_brightness)) * 100.0;
+    let percent = (brightness as f32).log(max_brightness as f32) * 100.0;
     Percent::new(percent).expect("percent calculation to always give a valid value")
 }
 
-#[derive(Clone, Copy)]
-enum UpdateAction {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateAction {
     Add,
     Sub,
     Set,
 }
 
-fn update_brightness(args: UpdateArgs, action: UpdateAction) -> Result<(), Box<dyn Error>> {
+/// Value given to `add`, `sub` or `set`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Level {
+    /// Percentage, with a `%` suffix.
+    Percent(Percent),
+    /// Raw brightness value, without a suffix.
+    Raw(Brightness),
+    /// Percentage relative to the current brightness, with a `+` or `-` prefix.
+    Relative(UpdateAction, Percent),
+}
+
+impl Level {
+    fn clap_parser(s: &str) -> Result<Self, String> {
+        if let Some((action, percent)) = s
+            .strip_prefix('+')
+            .map(|p| (UpdateAction::Add, p))
+            .or_else(|| s.strip_prefix('-').map(|p| (UpdateAction::Sub, p)))
+        {
+            // Like for `add` and `sub`, the value is always a percentage.
+            return Self::percent(percent).map(|percent| Self::Relative(action, percent));
+        }
+        match s.strip_suffix('%') {
+            Some(percent) => percent::clap_parser(percent).map(Self::Percent),
+            None => s.parse().map(Self::Raw).map_err(|_| {
+                "not a raw brightness value, add a `%` suffix for a percentage".to_string()
+            }),
+        }
+    }
+
+    /// Parser for `add` and `sub`, where the value is always a percentage and the `%`
+    /// suffix is optional.
+    fn step_parser(s: &str) -> Result<Self, String> {
+        Self::percent(s).map(Self::Percent)
+    }
+
+    fn percent(s: &str) -> Result<Percent, String> {
+        percent::clap_parser(s.strip_suffix('%').unwrap_or(s))
+    }
+
+    /// Step for `add` and `sub`, which is always a percentage.
+    fn step(self) -> Result<Percent, String> {
+        match self {
+            Self::Percent(percent) => Ok(percent),
+            Self::Raw(value) => Percent::new(value as f32)
+                .ok_or_else(|| format!("{value} is not a percentage between 0 and 100")),
+            Self::Relative(..) => Err("a `+` or `-` prefix can only be used with set".to_string()),
+        }
+    }
+}
+
+/// Resolve the percent to use for `add`/`sub` when it's not given in the command line.
+fn default_step(config: &Config) -> Result<Percent, String> {
+    match env::var(DEFAULT_STEP_ENV) {
+        Ok(value) => {
+            log::info!("{DEFAULT_STEP_ENV} = {value:?}");
+            percent::clap_parser(&value)
+                .map_err(|err| format!("invalid value for {DEFAULT_STEP_ENV} ({value:?}): {err}"))
+        }
+        Err(env::VarError::NotPresent) => Ok(config.default_step.unwrap_or(DEFAULT_STEP)),
+        Err(err) => Err(format!("invalid value for {DEFAULT_STEP_ENV}: {err}")),
+    }
+}
+
+/// Brightness resolved for a requested percentage, which may not map back to the same
+/// percentage because of the resolution of the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resolved {
+    pub brightness: Brightness,
+    /// Percentage that was asked for, after applying the action.
+    pub requested: Percent,
+    /// Percentage that `brightness` actually maps to.
+    pub percent: Percent,
+}
+
+impl Resolved {
+    fn new(
+        brightness: Brightness,
+        requested: Percent,
+        max_brightness: Brightness,
+        scale: Scale,
+    ) -> Self {
+        Self {
+            brightness,
+            requested,
+            percent: scale.percent(brightness, max_brightness),
+        }
+    }
+}
+
+/// Compute the new brightness of a device with a brightness of `current` after applying
+/// `action` with `step`.
+pub fn resolve_brightness(
+    current: Brightness,
+    max_brightness: Brightness,
+    action: UpdateAction,
+    step: Percent,
+    rounding: Option<Rounding>,
+    curve: Curve<'_>,
+) -> Resolved {
     use UpdateAction as UA;
 
-    let mut device = device::get_device(&args.filters.into())?;
+    let current_percent = curve.percent(current, max_brightness);
+    let requested = match action {
+        UA::Add => current_percent + step,
+        UA::Sub => current_percent - step,
+        UA::Set => step,
+    };
+    let rounding = rounding.unwrap_or(match action {
+        UA::Add => Rounding::Up,
+        UA::Sub => Rounding::Down,
+        UA::Set => Rounding::Nearest,
+    });
+    let mut brightness = brightness_from_percent(&requested, max_brightness, rounding, curve);
+
+    // On devices with low resolution a small step may not be enough to change the raw
+    // value, so always move it at least one unit in the requested direction, without
+    // going above the ceiling.
+    if brightness == current && step != Percent::MIN {
+        match action {
+            UA::Add => {
+                let ceiling =
+                    brightness_from_percent(&Percent::MAX, max_brightness, Rounding::Down, curve);
+                brightness = brightness.saturating_add(1).min(ceiling);
+            }
+            UA::Sub => brightness = brightness.saturating_sub(1),
+            UA::Set => {}
+        }
+    }
+
+    Resolved::new(brightness, requested, max_brightness, curve.scale)
+}
+
+/// Compute the new brightness for a device after applying `action`.
+fn new_brightness(
+    device: &Device,
+    action: UpdateAction,
+    step: Percent,
+    rounding: Option<Rounding>,
+    curve: Curve<'_>,
+) -> Resolved {
+    let resolved =
+        resolve_brightness(device.brightness, device.max_brightness, action, step, rounding, curve);
+
+    // The device resolution may be too coarse to represent the requested percentage.
+    if resolved.brightness == device.brightness
+        && resolved.requested != curve.percent(device.brightness, device.max_brightness)
+    {
+        log::warn!(
+            r#"brightness for device "{}" stays at {}, {:.2} can't be represented with a max brightness of {}"#,
+            device.name,
+            resolved.brightness,
+            resolved.requested,
+            device.max_brightness,
+        );
+    }
+
+    resolved
+}
+
+/// Apply `action` to the devices matching `args`. With `--all`, a device that fails is
+/// logged and the others are still updated. Returns whether all devices were updated.
+fn update_brightness(
+    mut args: UpdateArgs,
+    mut action: UpdateAction,
+    backend: &dyn BrightnessBackend,
+    cache: &DeviceCache,
+    format: PercentFormat,
+    table: Option<&LookupTable>,
+) -> Result<bool, Box<dyn Error>> {
+    // Serialize concurrent invocations, otherwise they may read the same brightness and
+    // overwrite each other's changes.
+    let _lock = if args.no_lock || args.simulate {
+        None
+    } else {
+        acquire_lock()
+            .inspect_err(|err| log::warn!("failed to acquire lock: {err}"))
+            .ok()
+            .flatten()
+    };
+
+    let filters = (&args.filters).into();
+    let mut devices = if args.all {
+        device::get_devices(&filters, cache)?.collect()
+    } else {
+        vec![device::get_device(&filters, cache)?]
+    };
+    if args.preserve_ratio {
+        let step;
+        (action, step) = preserve_ratio_step(&devices, &args, action, table)?;
+        args.value = Some(Level::Percent(step));
+        args.round.get_or_insert(Rounding::Nearest);
+    }
+
+    let mut total = 0.0;
+    let mut updated = 0;
+    for device in &mut devices {
+        let old_percent = args
+            .scale(device.class)
+            .percent(args.view(device), device.max_brightness);
+        let percent = match update_device(device, &args, action, backend, table) {
+            Ok(resolved) => resolved.percent,
+            // Like `restore`, update as many devices as possible.
+            Err(err) if args.all => {
+                log::error!(r#"failed to set brightness for device "{}": {err}"#, device.name);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        total += percent.get();
+        updated += 1;
+        if args.all {
+            writeln!(io::stdout(), "{}: {}", device.name, format.format(percent))?;
+        } else {
+            writeln!(io::stdout(), "{}", format.format(percent))?;
+        }
+
+        if args.log_history && !args.simulate {
+            let entry = HistoryEntry {
+                timestamp: rfc3339(SystemTime::now()),
+                device: &device.name,
+                old_percent,
+                new_percent: percent,
+            };
+            if let Err(err) = append_history(&entry) {
+                log::warn!("failed to write history: {err}");
+            }
+        }
+    }
+
+    // A single notification for all devices, with their average brightness.
+    if updated > 0 && !args.simulate {
+        let average =
+            Percent::new(total / updated as f32).expect("average of percentages to be valid");
+        if let Err(err) = backend.notify(average) {
+            log::warn!("failed to send notification: {err}");
+        }
+    }
+
+    Ok(updated == devices.len())
+}
 
-    let percent = match action {
-        UA::Add => brightness_to_percent(device.brightness, device.max_brightness) + args.percent,
-        UA::Sub => brightness_to_percent(device.brightness, device.max_brightness) - args.percent,
-        UA::Set => args.percent,
+/// For `--preserve-ratio`, the action and step that move the average brightness of
+/// `devices` to the requested percentage, applied to every device.
+fn preserve_ratio_step(
+    devices: &[Device],
+    args: &UpdateArgs,
+    action: UpdateAction,
+    table: Option<&LookupTable>,
+) -> Result<(UpdateAction, Percent), String> {
+    if action != UpdateAction::Set {
+        return Err("--preserve-ratio can only be used with set".to_string());
+    }
+    let Some(Level::Percent(target)) = args.value else {
+        return Err("--preserve-ratio needs a percentage (with a `%` suffix)".to_string());
+    };
+    let average = devices
+        .iter()
+        .map(|device| {
+            args.curve(table, device.class)
+                .percent(args.view(device), device.max_brightness)
+                .get()
+        })
+        .sum::<f32>()
+        / devices.len() as f32;
+    let delta = target.get() - average;
+    log::info!("average brightness = {average:.2}%, changing it by {delta:.2}%");
+    let step = Percent::new(delta.abs()).expect("difference of percentages to be valid");
+    if delta < 0.0 {
+        Ok((UpdateAction::Sub, step))
+    } else {
+        Ok((UpdateAction::Add, step))
+    }
+}
+
+/// Apply `action` to a single device, returning its new brightness. The brightness is
+/// resolved the same way when simulating, but nothing is written.
+fn update_device(
+    device: &mut Device,
+    args: &UpdateArgs,
+    action: UpdateAction,
+    backend: &dyn BrightnessBackend,
+    table: Option<&LookupTable>,
+) -> Result<Resolved, Box<dyn Error>> {
+    let value = match args.level {
+        Some(_) if action != UpdateAction::Set => {
+            return Err("--level can only be used with set".into());
+        }
+        Some(level) if level >= device.levels() => {
+            return Err(format!(
+                "level {level} is out of range, device \"{}\" has levels 0 to {}",
+                device.name, device.max_brightness
+            )
+            .into());
+        }
+        Some(level) => Level::Raw(level),
+        None => args
+            .value
+            .ok_or("a value is required to set the brightness")?,
     };
-    let brightness = brightness_from_percent(&percent, device.max_brightness);
 
+    if args.ceiling == Percent::MIN {
+        return Err("ceiling must be greater than 0".into());
+    }
+
+    let max = device.max_brightness;
+    // With `invert` the new brightness is computed as if the device wasn't inverted, and
+    // inverted back when written.
+    let raw = |brightness: Brightness| {
+        if args.invert {
+            max - brightness
+        } else {
+            brightness
+        }
+    };
+    let view = Device {
+        brightness: raw(device.brightness),
+        ..device.clone()
+    };
+    let curve = args.curve(table, device.class);
+
+    let mut resolved = match (action, value) {
+        (UpdateAction::Set, Level::Raw(value)) => {
+            let brightness = value.min(max);
+            let percent = curve.scale.percent(brightness, max);
+            Resolved::new(brightness, percent, max, curve.scale)
+        }
+        (action, value) => {
+            let step = value.step()?;
+            new_brightness(&view, action, step, args.round, curve)
+        }
+    };
+    if args.no_zero_backlight && raw(resolved.brightness) == 0 && device.class == Class::Backlight {
+        log::info!(r#"keeping backlight "{}" at 1 instead of turning it off"#, device.name);
+        resolved = Resolved::new(raw(1), resolved.requested, max, curve.scale);
+    }
+    if args.if_changed && raw(resolved.brightness) == device.brightness {
+        log::info!(r#"device "{}" is already at the new brightness"#, device.name);
+        return Ok(resolved);
+    }
     if !args.simulate {
-        let controller = device::Controller::new();
-        controller.set_brightness(&mut device, brightness)?;
+        let used = match args.duration {
+            Some(duration) => {
+                let fade = Fade {
+                    duration: Duration::from_millis(duration),
+                    ease: args.ease,
+                    curve,
+                };
+                fade.run(device, backend, view.brightness, resolved.brightness, raw)?
+            }
+            None => backend.set(device, raw(resolved.brightness))?,
+        };
+        if args.report_backend {
+            writeln!(io::stderr(), "backend: {used}")?;
+        }
+        if args.verify {
+            verify_brightness(device, raw(resolved.brightness));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Easing function for fades, applied to the elapsed fraction of the duration.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum Ease {
+    /// Constant speed
+    #[default]
+    Linear,
+    /// Start slowly and speed up
+    EaseIn,
+    /// Start quickly and slow down
+    EaseOut,
+}
+
+impl Ease {
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            Self::Linear => t,
+            Self::EaseIn => t * t,
+            Self::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+        }
+    }
+}
+
+/// Time between the steps of a fade.
+const FADE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Gradual change of brightness. Percentages are interpolated instead of raw values, so
+/// each step looks like the same change.
+struct Fade<'a> {
+    duration: Duration,
+    ease: Ease,
+    curve: Curve<'a>,
+}
+
+impl Fade<'_> {
+    /// Fade `device` from `from` to `to`, values which are converted with `raw` before
+    /// being written. Returns how the last value was written.
+    fn run(
+        &self,
+        device: &mut Device,
+        backend: &dyn BrightnessBackend,
+        from: Brightness,
+        to: Brightness,
+        raw: impl Fn(Brightness) -> Brightness,
+    ) -> Result<device::controller::Backend, Box<dyn Error>> {
+        let max = device.max_brightness;
+        let start = self.curve.percent(from, max).get();
+        let end = self.curve.percent(to, max).get();
+        let steps = (self.duration.as_millis() / FADE_INTERVAL.as_millis()).max(1) as u32;
+        log::debug!("fading from {from} to {to} in {steps} steps");
+
+        let mut last = from;
+        for i in 1..steps {
+            let t = self.ease.apply(i as f32 / steps as f32);
+            let percent = Percent::new((start + (end - start) * t).clamp(0.0, 100.0))
+                .expect("percent to be clamped");
+            let brightness = brightness_from_percent(&percent, max, Rounding::Nearest, self.curve);
+            if brightness != last {
+                backend.set(device, raw(brightness))?;
+                last = brightness;
+            }
+            thread::sleep(FADE_INTERVAL);
+        }
+        // The last step is always written, so the exact value is reached.
+        Ok(backend.set(device, raw(to))?)
+    }
+}
+
+/// Warn if the brightness of `device` isn't `expected` when read back.
+fn verify_brightness(device: &mut Device, expected: Brightness) {
+    match device.refresh() {
+        Ok(actual) if actual != expected => log::warn!(
+            r#"brightness of device "{}" is {actual} after setting it to {expected}"#,
+            device.name
+        ),
+        Ok(_) => log::debug!(r#"verified brightness of device "{}""#, device.name),
+        Err(err) => log::warn!("failed to verify brightness: {err}"),
+    }
+}
+
+/// Restore the brightness of saved devices, only showing the changes if there's no
+/// `backend`. With `ramp`, each device fades to its saved brightness over that time, one
+/// after the other. See [`saved_device`] for `remap` and `sysfs_root`. Returns whether
+/// all devices were restored.
+fn restore(
+    save_data: Vec<SaveData>,
+    backend: Option<&dyn BrightnessBackend>,
+    precision: usize,
+    ramp: Option<Duration>,
+    remap: bool,
+    sysfs_root: Option<&Path>,
+    cache: &DeviceCache,
+) -> io::Result<bool> {
+    let mut fail_to_restore = false;
+
+    // Explicitly handle all errors to allow restoring as much devices as possible.
+    for data in save_data {
+        match saved_device(&data, remap, sysfs_root, cache) {
+            Ok((mut device, brightness)) => {
+                let Some(backend) = backend else {
+                    let max = device.max_brightness;
+                    let scale = Scale::of_class(device.class);
+                    let current = scale.percent(device.brightness, max);
+                    let target = scale.percent(brightness.min(max), max);
+                    writeln!(
+                        io::stdout(),
+                        "{}: {current:.precision$} -> {target:.precision$}",
+                        device.name
+                    )?;
+                    continue;
+                };
+                // Color must be set first, since the kernel computes the
+                // brightness of each channel when `brightness` is written.
+                let set = || -> Result<_, Box<dyn Error>> {
+                    if let Some(values) = data.multi_intensity {
+                        backend.set_multi_intensity(&mut device, values)?;
+                    }
+                    match ramp {
+                        Some(duration) => {
+                            let fade = Fade {
+                                duration,
+                                ease: Ease::Linear,
+                                curve: Curve {
+                                    scale: Scale::of_class(device.class),
+                                    ..Curve::default()
+                                },
+                            };
+                            let (from, to) =
+                                (device.brightness, brightness.min(device.max_brightness));
+                            fade.run(&mut device, backend, from, to, |brightness| brightness)
+                        }
+                        None => Ok(backend.set(&mut device, brightness)?),
+                    }
+                };
+                if let Err(err) = set() {
+                    fail_to_restore = true;
+                    log::error!(r#"failed to set brightness for device "{}": {err}"#, device.name);
+                } else {
+                    log::info!(
+                        r#"restored device "{}" with brightness: {}"#,
+                        device.name,
+                        device.brightness
+                    );
+                }
+            }
+            Err(err) => {
+                fail_to_restore = true;
+                log::error!("{err}");
+            }
+        }
     }
 
-    let percent = brightness_to_percent(brightness, device.max_brightness);
-    writeln!(io::stdout(), "{percent:.2}")?;
+    Ok(!fail_to_restore)
+}
+
+/// Read saved data from a file. Malformed CSV rows are reported and skipped, so the rest
+/// of the devices are restored, setting `failed`.
+fn read_save_data(
+    path: &Path,
+    format: InputFormat,
+    failed: &mut bool,
+) -> Result<Vec<SaveData>, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|err| device::PathError::new(err, path))?;
+    let save_data = match format {
+        InputFormat::Json => serde_json::from_str(&content)
+            .map_err(|err| format!("invalid save file \"{}\": {err}", path.display()))?,
+        InputFormat::Csv => content
+            .lines()
+            .filter(|row| !row.trim().is_empty())
+            .filter_map(|row| {
+                SaveData::from_csv_row(row)
+                    .inspect_err(|err| {
+                        *failed = true;
+                        log::error!("{err}");
+                    })
+                    .ok()
+            })
+            .collect(),
+    };
+    Ok(save_data)
+}
+
+/// Find the device of saved data and the brightness to restore.
+///
+/// If the saved path no longer exists (e.g. it was saved with its canonical path, or a
+/// driver renamed the device), the device with the same name in the same class under
+/// `sysfs_root` is used, or with `remap` the only device of the class if there's one. The
+/// brightness is then converted to keep the same percentage.
+fn saved_device(
+    data: &SaveData,
+    remap: bool,
+    sysfs_root: Option<&Path>,
+    cache: &DeviceCache,
+) -> Result<(Device, Brightness), device::PathError> {
+    let err = match Device::from_path(&data.path) {
+        Ok(device) => {
+            let brightness = saved_brightness(data, &device);
+            return Ok((device, brightness));
+        }
+        Err(err) => err,
+    };
+    let Some(class) = data.class.filter(|_| !data.path.exists()) else {
+        return Err(err);
+    };
+
+    let name = data.path.file_name().unwrap_or_default().to_string_lossy();
+    let mut filters = device::DeviceFilters {
+        classes: vec![class],
+        device_name: Some(name.into_owned()),
+        sysfs_root: sysfs_root.map(Path::to_path_buf),
+        ..Default::default()
+    };
+    let device = match device::get_device(&filters, cache) {
+        Ok(device) => device,
+        Err(_) if remap => {
+            filters.device_name = None;
+            let Ok(devices) = device::get_devices(&filters, cache) else {
+                return Err(err);
+            };
+            let mut devices: Vec<_> = devices.collect();
+            if devices.len() != 1 {
+                return Err(err);
+            }
+            devices.remove(0)
+        }
+        Err(_) => return Err(err),
+    };
+    log::warn!(
+        r#"device "{}" not found, restoring "{}" instead"#,
+        data.path.display(),
+        device.path.display()
+    );
+
+    let brightness = saved_brightness(data, &device);
+    Ok((device, brightness))
+}
+
+/// Brightness to restore `device` to, computed from the saved percentage if there's one,
+/// or keeping the same percentage if the device was saved with a different max.
+fn saved_brightness(data: &SaveData, device: &Device) -> Brightness {
+    let curve = Curve {
+        scale: Scale::of_class(device.class),
+        ..Curve::default()
+    };
+    let percent = match (data.percent, data.max_brightness) {
+        (Some(percent), _) => percent,
+        (None, Some(max)) if max != device.max_brightness => {
+            curve.percent(data.brightness.min(max), max)
+        }
+        _ => return data.brightness,
+    };
+    brightness_from_percent(&percent, device.max_brightness, Rounding::Nearest, curve)
+}
+
+/// Line of the file written by `--log-history`.
+#[derive(Serialize)]
+struct HistoryEntry<'a> {
+    timestamp: String,
+    device: &'a str,
+    old_percent: Percent,
+    new_percent: Percent,
+}
 
+/// Append a brightness change to `history.jsonl` in the state directory.
+fn append_history(entry: &HistoryEntry<'_>) -> Result<(), Box<dyn Error>> {
+    let dir = get_xdg_state_path().ok_or("could not determine a path for the history file")?;
+    fs::create_dir_all(&dir).map_err(|err| device::PathError::new(err, &dir))?;
+    let path = dir.join("history.jsonl");
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    // A single write in append mode, so concurrent invocations don't mix their lines.
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&line))
+        .map_err(|err| device::PathError::new(err, &path))?;
     Ok(())
 }
 
+/// Decimals of printed percentages.
+const DEFAULT_PRECISION: usize = 2;
+const MAX_PRECISION: usize = 6;
+
+/// How single percentages are printed, e.g. by `get` and `set`.
+#[derive(Debug, Clone, Copy)]
+struct PercentFormat {
+    precision: usize,
+    /// Use a comma as the decimal separator, as in some locales.
+    decimal_comma: bool,
+}
+
+impl PercentFormat {
+    fn format(self, percent: Percent) -> String {
+        let text = format!("{percent:.*}", self.precision);
+        if self.decimal_comma {
+            text.replace('.', ",")
+        } else {
+            text
+        }
+    }
+}
+
+/// Environment variable to override the directory where state is stored.
+const STATE_DIR_ENV: &str = "LIGHTER_STATE_DIR";
+
 fn get_xdg_state_path() -> Option<PathBuf> {
+    let state_dir = env::var_os(STATE_DIR_ENV);
+    log::info!("{STATE_DIR_ENV} = {state_dir:?}");
+    if let Some(path) = state_dir
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+    {
+        return Some(path);
+    }
+
     let path = env::var_os("XDG_STATE_HOME");
     log::info!("XDG_STATE_HOME = {path:?}");
     path.filter(|s| !s.is_empty())
@@ -220,6 +1074,39 @@ fn get_xdg_state_path() -> Option<PathBuf> {
         .map(|p| p.join(BIN_NAME))
 }
 
+/// Write `content` to a temporary file next to `path` and rename it into place, so
+/// `path` is never left with partial content. The temporary file is removed on error.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), device::PathError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+    let write = || {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    };
+    write().map_err(|err| {
+        _ = fs::remove_file(&temp);
+        device::PathError::new(err, path)
+    })
+}
+
+/// Take an exclusive lock on a file in the state directory, which is released when the
+/// returned file is dropped.
+fn acquire_lock() -> Result<Option<fs::File>, device::PathError> {
+    let Some(dir) = get_xdg_state_path() else {
+        log::warn!("could not determine a path for the lock file");
+        return Ok(None);
+    };
+    fs::create_dir_all(&dir).map_err(|err| device::PathError::new(err, &dir))?;
+    let path = dir.join("lock");
+    let file = fs::File::create(&path).map_err(|err| device::PathError::new(err, &path))?;
+    log::debug!("waiting for lock on {}", path.display());
+    file.lock()
+        .map_err(|err| device::PathError::new(err, &path))?;
+    Ok(Some(file))
+}
+
 fn get_save_path(default: Option<PathBuf>) -> io::Result<PathBuf> {
     default
         .or_else(|| Some(get_xdg_state_path()?.join("device-data.json")))
@@ -243,6 +1130,20 @@ fn validate_file_path(opt: &str) -> Result<PathBuf, String> {
 pub struct SaveData {
     pub path: PathBuf,
     pub brightness: Brightness,
+    #[serde(default)]
+    pub multi_intensity: Option<Vec<Brightness>>,
+    /// Used to find the device if its path no longer exists.
+    #[serde(default)]
+    pub class: Option<Class>,
+    /// Used to keep the same percentage if the device is found with a different max.
+    #[serde(default)]
+    pub max_brightness: Option<Brightness>,
+    /// When the data was saved, as an RFC 3339 timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saved_at: Option<String>,
+    /// Restored instead of `brightness`, so it doesn't depend on the max brightness.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percent: Option<Percent>,
 }
 
 impl From<Device> for SaveData {
@@ -250,50 +1151,437 @@ impl From<Device> for SaveData {
         Self {
             path: device.path,
             brightness: device.brightness,
+            multi_intensity: device.multi_intensity,
+            class: Some(device.class),
+            max_brightness: Some(device.max_brightness),
+            saved_at: None,
+            percent: None,
         }
     }
 }
 
-#[derive(Serialize)]
+/// Format a time as an RFC 3339 timestamp in UTC, e.g. `2024-03-20T12:07:00Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    let (hour, minute, second) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    // Civil date from the days since the epoch, see:
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+impl SaveData {
+    /// Parse a row of `path,brightness`, or a row as written by `info --format csv`.
+    fn from_csv_row(row: &str) -> Result<Self, String> {
+        let fields: Vec<_> = row.split(',').map(str::trim).collect();
+        let (path, brightness, class, max_brightness) = match fields[..] {
+            [path, brightness] => (path, brightness, None, None),
+            [_, path, class, brightness, ref rest @ ..] => {
+                (path, brightness, Some(class), rest.first())
+            }
+            _ => return Err(format!("invalid row {row:?}: expected path and brightness")),
+        };
+        let brightness = brightness
+            .parse()
+            .map_err(|err| format!("invalid row {row:?}: {err}"))?;
+        Ok(Self {
+            path: PathBuf::from(path),
+            brightness,
+            multi_intensity: None,
+            class: class.and_then(|class| class.parse().ok()),
+            max_brightness: max_brightness.and_then(|max| max.parse().ok()),
+            saved_at: None,
+            percent: None,
+        })
+    }
+}
+
+/// Output of `info --format json`.
+#[derive(Serialize, JsonSchema)]
+struct DevicesOutput {
+    devices: Vec<DeviceOutput>,
+}
+
+#[derive(Serialize, JsonSchema)]
 struct DeviceOutput {
     name: String,
     path: PathBuf,
     class: Class,
     brightness: Brightness,
     max_brightness: Brightness,
+    percent: Percent,
+    /// Value of the device's `type` attribute, if it has one.
+    #[serde(rename = "type")]
+    device_type: Option<String>,
+    /// Value of the device's `scale` attribute, if it has one.
+    scale: Option<String>,
+    /// Active trigger of LEDs that support them.
+    trigger: Option<String>,
+    /// Number of discrete hardware levels, including off.
+    levels: Brightness,
+    /// Parameters of the conversion to percentages, only with `info --show-math`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formula: Option<Formula>,
+}
+
+/// Parameters of the perceptual conversion for a max brightness, where
+/// `percent = 100 * log10(brightness) / log10(max_brightness)`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+struct Formula {
+    /// `log10(max_brightness)`.
+    log10_max_brightness: f32,
+    /// Percentage added each time the raw brightness is multiplied by 10.
+    percent_per_decade: f32,
+}
+
+impl Formula {
+    /// Formula used by `scale`, linear percentages and devices with less than 3 levels
+    /// don't use one.
+    fn new(scale: Scale, max_brightness: Brightness) -> Option<Self> {
+        if !matches!(scale, Scale::Perceptual) || max_brightness <= 1 {
+            return None;
+        }
+        let log10_max_brightness = (max_brightness as f32).log10();
+        Some(Self {
+            log10_max_brightness,
+            percent_per_decade: 100.0 / log10_max_brightness,
+        })
+    }
 }
 
 impl From<Device> for DeviceOutput {
     #[inline]
     fn from(device: Device) -> Self {
+        let scale = Scale::of_class(device.class);
+        Self::new(device, scale)
+    }
+}
+
+impl DeviceOutput {
+    fn new(device: Device, scale: Scale) -> Self {
         Self {
+            percent: scale.percent(device.brightness, device.max_brightness),
+            levels: device.levels(),
+            formula: None,
             name: device.name,
             path: device.path,
             class: device.class,
             brightness: device.brightness,
             max_brightness: device.max_brightness,
+            device_type: device.device_type,
+            scale: device.scale,
+            trigger: device.trigger,
+        }
+    }
+}
+
+/// Class given in the command line, which unlike [`Class`] can refer to all classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ClassFilter {
+    Leds,
+    Backlight,
+    Ddc,
+    All,
+}
+
+impl ClassFilter {
+    fn classes(self) -> &'static [Class] {
+        match self {
+            Self::Leds => &[Class::Leds],
+            Self::Backlight => &[Class::Backlight],
+            Self::Ddc => &[Class::Ddc],
+            Self::All => &[Class::Backlight, Class::Leds, Class::Ddc],
+        }
+    }
+}
+
+impl From<Class> for ClassFilter {
+    fn from(class: Class) -> Self {
+        match class {
+            Class::Leds => Self::Leds,
+            Class::Backlight => Self::Backlight,
+            Class::Ddc => Self::Ddc,
         }
     }
 }
 
 #[derive(Args)]
 struct FilterArgs {
-    /// Filter by device class
-    #[arg(short, long, value_enum)]
-    class: Option<Class>,
+    /// Filter by device class, multiple classes can be separated by commas
+    #[arg(short, long, value_enum, value_delimiter = ',')]
+    class: Vec<ClassFilter>,
 
     /// Filter by device name
-    #[arg(short, long)]
+    #[arg(short, long, add = ArgValueCompleter::new(complete_device_name))]
     device: Option<String>,
-}
 
-#[derive(Args)]
-struct UpdateArgs {
-    /// Value in the range [0, 100], supports decimals (e.g. 10.5).
-    #[arg(value_parser = percent::clap_parser)]
-    percent: Percent,
+    /// Filter by part of the device name (e.g. `intel` for `intel_backlight`)
+    #[arg(long, value_name = "STR")]
+    device_contains: Option<String>,
+
+    /// Target the keyboard backlight, short for `--class leds --device-contains
+    /// kbd_backlight`
+    #[arg(short, long, conflicts_with_all = ["class", "device", "device_contains"])]
+    keyboard: bool,
+
+    /// Exclude devices by name, can be given multiple times
+    #[arg(
+        short,
+        long,
+        value_name = "NAME",
+        add = ArgValueCompleter::new(complete_device_name)
+    )]
+    exclude: Vec<String>,
+
+    /// Directory containing the device classes [default: /sys/class]
+    #[arg(long, value_name = "PATH")]
+    sysfs_root: Option<PathBuf>,
+
+    /// Resolve symbolic links to devices, so they are named after the real device
+    #[arg(long)]
+    follow_symlinks: bool,
 
-    /// Do not modify any device, only pretend to do it.
+    /// When a single device is used, prefer backlights by type: `raw`, then `platform`,
+    /// then `firmware`
+    #[arg(long)]
+    prefer_type: bool,
+
+    /// Leave out devices with a max brightness lower than this (e.g. 2 to skip on/off
+    /// LEDs)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    min_resolution: Brightness,
+
+    /// Leave out devices that can't be written directly (e.g. without a udev rule), even
+    /// if they could be written through logind
+    #[arg(long)]
+    only_writable: bool,
+
+    /// Fail if any device of the searched classes or its brightness can't be read, instead
+    /// of skipping it with a warning (e.g. to catch permission or driver regressions)
+    #[arg(long)]
+    strict: bool,
+}
+
+fn complete_device_name(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    device::device_names()
+        .filter(|name| name.starts_with(&*current))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+#[derive(Args)]
+struct UpdateArgs {
+    /// Percentage in the range [0, 100] with a `%` suffix, supports decimals (e.g.
+    /// 10.5%), or a raw brightness value without it (e.g. 800).
+    ///
+    /// For `add` and `sub` the value is always a percentage, with or without the suffix
+    /// (e.g. 2.5), and it defaults to the value of `LIGHTER_DEFAULT_STEP`, then to
+    /// `default_step` from the configuration file, or 5 if neither is set. For `set`, a
+    /// `+` or `-` prefix (e.g. +10) adds or subtracts a percentage like `add` and `sub`.
+    #[arg(value_parser = Level::clap_parser, allow_hyphen_values = true)]
+    value: Option<Level>,
+
+    /// Set the Nth discrete hardware level, from 0 (off) to the max brightness. Only for
+    /// `set`
+    #[arg(long, value_name = "N", conflicts_with = "value")]
+    level: Option<Brightness>,
+
+    /// Do not modify any device, only pretend to do it.
+    #[arg(short, long)]
+    simulate: bool,
+
+    /// How to round to a raw brightness value [default: `up` for `add`, `down` for `sub`
+    /// and `nearest` for `set`]
+    #[arg(long, value_enum)]
+    round: Option<Rounding>,
+
+    /// Percentage of the max brightness that 100% maps to, to limit the usable range
+    #[arg(long, default_value = "100", value_parser = percent::clap_parser)]
+    ceiling: Percent,
+
+    /// Exponent for percentages, values above 1 make low percentages darker (e.g. for OLED
+    /// panels) and values below 1 make them brighter
+    #[arg(long, default_value_t = 1.0, value_parser = gamma_parser)]
+    gamma: f32,
+
+    /// How percentages map to raw values [default: `linear` for LEDs, `perceptual` for
+    /// other devices]
+    #[arg(long = "curve", value_enum, value_name = "CURVE")]
+    scale: Option<Scale>,
+
+    /// Do not wait for other instances modifying the brightness to finish.
+    #[arg(long)]
+    no_lock: bool,
+
+    /// Append the change to `history.jsonl` in the state directory
+    #[arg(long)]
+    log_history: bool,
+
+    /// Never set backlights to 0, which turns off the screen on most laptops (LEDs can
+    /// still be turned off)
+    #[arg(long)]
+    no_zero_backlight: bool,
+
+    /// Treat the device as inverted, where 0 is fully on and the max brightness is off
+    #[arg(long)]
+    invert: bool,
+
+    /// Read the brightness back after writing it, and warn if it differs from the value
+    /// set (e.g. the hardware clamped it)
+    #[arg(long)]
+    verify: bool,
+
+    /// Skip writing when the device is already at the new brightness, e.g. to avoid
+    /// waking up hardware when run on a timer
+    #[arg(long)]
+    if_changed: bool,
+
+    /// Print how the brightness was written to stderr (`dbus`, `sysfs`, `pkexec` or
+    /// `ddcutil`), e.g. to debug permissions
+    #[arg(long)]
+    report_backend: bool,
+
+    /// Change the brightness gradually over this many milliseconds
+    #[arg(long, value_name = "MS")]
+    duration: Option<u64>,
+
+    /// Change all devices matching the filters, instead of only the first one
+    #[arg(long)]
+    all: bool,
+
+    /// With `--all`, move the average brightness to the given percentage by changing all
+    /// devices by the same amount, keeping the differences between them. Only for `set`
+    #[arg(long, requires = "all")]
+    preserve_ratio: bool,
+
+    /// Easing of `--duration` fades
+    #[arg(long, value_enum, default_value_t, requires = "duration")]
+    ease: Ease,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+}
+
+impl UpdateArgs {
+    /// Brightness of `device` as seen with `--invert`.
+    fn view(&self, device: &Device) -> Brightness {
+        if self.invert {
+            device.max_brightness - device.brightness
+        } else {
+            device.brightness
+        }
+    }
+
+    /// Arguments to set the brightness to `percent`, for commands that pick it
+    /// themselves.
+    fn set(percent: Percent, simulate: bool, filters: FilterArgs) -> Self {
+        Self {
+            value: Some(Level::Percent(percent)),
+            level: None,
+            simulate,
+            round: None,
+            ceiling: Percent::MAX,
+            gamma: 1.0,
+            scale: None,
+            no_lock: false,
+            log_history: false,
+            no_zero_backlight: false,
+            invert: false,
+            verify: false,
+            if_changed: false,
+            report_backend: false,
+            duration: None,
+            ease: Ease::Linear,
+            all: false,
+            preserve_ratio: false,
+            filters,
+        }
+    }
+
+    /// Scale for devices of `class`, unless given with `--curve`.
+    fn scale(&self, class: Class) -> Scale {
+        self.scale.unwrap_or(Scale::of_class(class))
+    }
+
+    fn curve<'a>(&self, table: Option<&'a LookupTable>, class: Class) -> Curve<'a> {
+        Curve {
+            ceiling: self.ceiling,
+            gamma: self.gamma,
+            table,
+            scale: self.scale(class),
+        }
+    }
+}
+
+#[derive(Args)]
+struct AutoArgs {
+    /// Latitude in degrees, positive to the north
+    #[arg(long, allow_negative_numbers = true, value_parser = solar::latitude_parser)]
+    lat: f64,
+
+    /// Longitude in degrees, positive to the east
+    #[arg(long, allow_negative_numbers = true, value_parser = solar::longitude_parser)]
+    lon: f64,
+
+    /// Brightness percentage to use at night
+    #[arg(long, default_value = "10", value_parser = percent::clap_parser)]
+    min: Percent,
+
+    /// Brightness percentage to use at solar noon
+    #[arg(long, default_value = "100", value_parser = percent::clap_parser)]
+    max: Percent,
+
+    /// Do not modify any device, only pretend to do it.
+    #[arg(short, long)]
+    simulate: bool,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+}
+
+impl AutoArgs {
+    /// Brightness percentage for the current position of the sun.
+    fn percent(&self) -> Result<Percent, String> {
+        if self.min.get() > self.max.get() {
+            return Err(format!("min ({}) is greater than max ({})", self.min, self.max));
+        }
+        let coords = solar::Coordinates {
+            latitude: self.lat,
+            longitude: self.lon,
+        };
+        let daylight = solar::daylight(coords, std::time::SystemTime::now()) as f32;
+        // Only NaN isn't a ratio, which can only happen with the sun overhead.
+        let daylight = Percent::from_ratio(daylight).unwrap_or(Percent::MAX);
+        log::info!("daylight = {daylight:.2}%");
+        let percent = self.min.get() + (self.max.get() - self.min.get()) * daylight.get() / 100.0;
+        Ok(Percent::new(percent).unwrap_or(self.max))
+    }
+}
+
+#[derive(Args)]
+struct PowerArgs {
+    /// Brightness percentage to use when running on battery
+    #[arg(long, value_parser = percent::clap_parser)]
+    on_battery: Percent,
+
+    /// Brightness percentage to use when plugged in
+    #[arg(long, default_value = "100", value_parser = percent::clap_parser)]
+    on_ac: Percent,
+
+    /// Do not modify any device, only pretend to do it.
     #[arg(short, long)]
     simulate: bool,
 
@@ -302,55 +1590,444 @@ struct UpdateArgs {
 }
 
 #[derive(Copy, Clone, Default, ValueEnum)]
+enum Aggregate {
+    /// Use the first matching device
+    #[default]
+    First,
+    /// Average across all matching devices
+    Avg,
+    /// Minimum across all matching devices
+    Min,
+    /// Maximum across all matching devices
+    Max,
+}
+
+impl Aggregate {
+    /// Combine the brightness of devices, reading `actual_brightness` if `actual` is set.
+    /// With `invert`, devices are fully on at 0 and off at their max brightness. Without
+    /// a `scale`, each device uses the one for its class.
+    fn get_percent(
+        self,
+        filters: &device::DeviceFilters,
+        actual: bool,
+        invert: bool,
+        scale: Option<Scale>,
+        cache: &DeviceCache,
+    ) -> Result<Percent, Box<dyn Error>> {
+        let percents = device::get_devices(filters, cache)?
+            .map(|mut device| {
+                if actual {
+                    device.read_actual_brightness()?;
+                }
+                let brightness = if invert {
+                    device.max_brightness - device.brightness
+                } else {
+                    device.brightness
+                };
+                let scale = scale.unwrap_or(Scale::of_class(device.class));
+                Ok(scale.percent(brightness, device.max_brightness).get())
+            })
+            .collect::<Result<Vec<_>, device::PathError>>()?;
+
+        let value = match self {
+            Aggregate::First => percents[0],
+            Aggregate::Avg => percents.iter().sum::<f32>() / percents.len() as f32,
+            Aggregate::Min => percents.iter().copied().fold(f32::INFINITY, f32::min),
+            Aggregate::Max => percents.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        };
+        // `get_devices` never returns an empty iterator, so the value is always valid.
+        Ok(Percent::new(value).expect("aggregate of percentages to be a percentage"))
+    }
+}
+
+#[derive(Args)]
+struct GetArgs {
+    /// How to combine the brightness of multiple devices
+    #[arg(short, long, value_enum, default_value_t)]
+    aggregate: Aggregate,
+
+    /// Format of the percentage, text formats print the plain number
+    #[arg(short, long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Read the brightness reported by the hardware (`actual_brightness`) when available
+    #[arg(long)]
+    actual: bool,
+
+    /// Report the brightness as a plain percentage of the max brightness, instead of
+    /// adjusting it to human perception
+    #[arg(long)]
+    linear_percent: bool,
+
+    /// How percentages are computed from raw values [default: `linear` for LEDs,
+    /// `perceptual` for other devices]
+    #[arg(
+        long = "curve",
+        value_enum,
+        value_name = "CURVE",
+        conflicts_with = "linear_percent"
+    )]
+    scale: Option<Scale>,
+
+    /// Treat devices as inverted, where 0 is fully on and the max brightness is off
+    #[arg(long)]
+    invert: bool,
+
+    /// Show the percentage with a bar, e.g. `[████░░░░░░] 40%`
+    #[arg(long, conflicts_with = "format")]
+    bar: bool,
+
+    /// Number of characters of the bar
+    #[arg(long, value_name = "N", default_value_t = 20, requires = "bar")]
+    bar_width: usize,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+}
+
+/// Bar of `width` characters filled up to `percent`.
+fn progress_bar(percent: Percent, width: usize) -> String {
+    let filled = ((percent.get() / 100.0 * width as f32).round() as usize).min(width);
+    format!("[{}{}]", "█".repeat(filled), "░".repeat(width - filled))
+}
+
+/// Table of each integer percentage and the raw brightness it converts to, marking the
+/// ones that give the same brightness as the previous percentage.
+fn write_calibration(
+    mut output: impl Write,
+    max_brightness: Brightness,
+    curve: Curve<'_>,
+) -> io::Result<()> {
+    use crate::colors::{Reset as R, YELLOW};
+
+    writeln!(output, "percent\traw")?;
+    let mut previous = None;
+    let mut distinct = 0;
+    for percent in 0..=100 {
+        let percent = Percent::new(percent as f32).expect("integers up to 100 to be valid");
+        let raw = brightness_from_percent(&percent, max_brightness, Rounding::Nearest, curve);
+        if previous == Some(raw) {
+            writeln!(output, "{percent:.0}\t{raw} {YELLOW}*{R}")?;
+        } else {
+            writeln!(output, "{percent:.0}\t{raw}")?;
+            distinct += 1;
+        }
+        previous = Some(raw);
+    }
+    writeln!(
+        output,
+        "# {distinct} of 101 percentages give a distinct brightness, * marks repeated values"
+    )
+}
+
+/// Summary line like `# 3 devices (2 backlight, 1 led)`.
+fn device_summary(backlight: usize, leds: usize, displays: usize) -> String {
+    let plural = |n| if n == 1 { "" } else { "s" };
+    let total = backlight + leds + displays;
+    let mut summary = format!("# {total} device{}", plural(total));
+    let mut classes = Vec::new();
+    if backlight > 0 {
+        classes.push(format!("{backlight} backlight"));
+    }
+    if leds > 0 {
+        classes.push(format!("{leds} led{}", plural(leds)));
+    }
+    if displays > 0 {
+        classes.push(format!("{displays} display{}", plural(displays)));
+    }
+    if !classes.is_empty() {
+        summary.push_str(&format!(" ({})", classes.join(", ")));
+    }
+    summary
+}
+
+/// How percentages are computed from raw brightness values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Scale {
+    /// Adjusted to human perception, see [`brightness_to_percent`]
+    #[default]
+    Perceptual,
+    /// Plain ratio of the brightness to the max brightness
+    Linear,
+}
+
+impl Scale {
+    fn percent(self, brightness: Brightness, max_brightness: Brightness) -> Percent {
+        match self {
+            Self::Perceptual => brightness_to_percent(brightness, max_brightness),
+            Self::Linear if max_brightness == 0 => Percent::MIN,
+            Self::Linear => Percent::from_ratio(brightness as f32 / max_brightness as f32)
+                .unwrap_or(Percent::MAX),
+        }
+    }
+
+    /// Default scale of a class: LEDs are often on/off or status indicators, where a
+    /// plain ratio makes more sense than a perceptual one.
+    pub fn of_class(class: Class) -> Self {
+        match class {
+            Class::Leds => Self::Linear,
+            Class::Backlight | Class::Ddc => Self::Perceptual,
+        }
+    }
+
+    /// Scale given with `--curve` or `--linear-percent`, if any.
+    fn from_args(scale: Option<Self>, linear: bool) -> Option<Self> {
+        scale.or(linear.then_some(Self::Linear))
+    }
+}
+
+/// Options for [`OutputFormat::write`].
+#[derive(Debug, Clone, Copy)]
+struct WriteOptions {
+    /// Write a footer with the number of devices, only in plain format.
+    summary: bool,
+    /// Number of decimals of percentages in text formats.
+    precision: usize,
+    /// Scale of all devices, otherwise the one for their class.
+    scale: Option<Scale>,
+    /// Strip styles even if the color choice forces them, e.g. when writing to a file.
+    no_color: bool,
+    /// Include the parameters of the percentage conversion, not in CSV and TSV.
+    show_math: bool,
+}
+
+#[derive(Copy, Clone, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum OutputFormat {
     #[default]
     Plain,
     Json,
+    /// Like `json`, leaving out fields without a value
+    CompactJson,
+    /// One JSON object per line (NDJSON)
+    #[value(alias = "ndjson")]
+    #[serde(alias = "ndjson")]
     JsonLines,
     Csv,
+    Tsv,
+    /// Pango markup for status bars, colored by brightness level
+    Pango,
+}
+
+/// Output of `version --json`, captured by the build script. Fields are empty if
+/// they couldn't be determined.
+#[derive(Serialize)]
+struct VersionOutput {
+    version: &'static str,
+    /// Short hash of the commit it was built from.
+    git: &'static str,
+    rustc: &'static str,
+}
+
+impl VersionOutput {
+    const CURRENT: Self = Self {
+        version: env!("CARGO_PKG_VERSION"),
+        git: env!("BRIGHTER_GIT_HASH"),
+        rustc: env!("BRIGHTER_RUSTC_VERSION"),
+    };
+}
+
+fn or_unknown(value: &str) -> &str {
+    if value.is_empty() { "unknown" } else { value }
+}
+
+/// Output of `get --format json`.
+#[derive(Serialize)]
+struct PercentOutput {
+    percent: Percent,
+}
+
+/// Color for a brightness level in Pango markup.
+fn pango_color(percent: Percent) -> &'static str {
+    match percent.get() {
+        p if p < 34.0 => "#5f87af",
+        p if p < 67.0 => "#ffcc00",
+        _ => "#ff8700",
+    }
+}
+
+/// Escape text to be included in Pango markup.
+fn pango_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+}
+
+/// Remove the fields of objects whose value is `null`, recursively.
+fn strip_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
 }
 
 impl OutputFormat {
-    fn write<O, I>(self, mut output: O, devices: I) -> io::Result<()>
+    /// Write a single percentage, as printed by `get`. Text formats write the plain
+    /// number.
+    fn write_percent(
+        self,
+        mut output: impl Write,
+        percent: Percent,
+        format: PercentFormat,
+    ) -> io::Result<()> {
+        match self {
+            OutputFormat::Plain | OutputFormat::Csv | OutputFormat::Tsv => {
+                writeln!(output, "{}", format.format(percent))
+            }
+            OutputFormat::Json | OutputFormat::CompactJson | OutputFormat::JsonLines => {
+                serde_json::to_writer(&mut output, &PercentOutput { percent })?;
+                writeln!(output)
+            }
+            OutputFormat::Pango => writeln!(
+                output,
+                "<span foreground='{}'>{}%</span>",
+                pango_color(percent),
+                format.format(percent),
+            ),
+        }
+    }
+
+    /// Write devices to `output`, styles are stripped according to the color choice and
+    /// whether `output` is a terminal, or always with `no_color`.
+    fn write<O, I>(self, output: O, devices: I, options: WriteOptions) -> io::Result<()>
     where
-        O: Write,
+        O: anstream::stream::RawStream + anstream::stream::AsLockedWrite,
         I: Iterator<Item = Device>,
     {
         use crate::colors::{BLUE, CYAN, GREEN, MAGENTA, Reset as R, YELLOW};
+
+        let WriteOptions {
+            summary,
+            precision,
+            scale,
+            no_color,
+            show_math,
+        } = options;
+        let mut output = if no_color {
+            anstream::AutoStream::never(output)
+        } else {
+            anstream::AutoStream::auto(output)
+        };
+
+        let scale = |device: &Device| scale.unwrap_or(Scale::of_class(device.class));
+        let percent =
+            |device: &Device| scale(device).percent(device.brightness, device.max_brightness);
+        let formula = |device: &Device| {
+            Formula::new(scale(device), device.max_brightness).filter(|_| show_math)
+        };
+        let output_of = |device: Device| {
+            let scale = scale(&device);
+            DeviceOutput {
+                formula: formula(&device),
+                ..DeviceOutput::new(device, scale)
+            }
+        };
         match self {
             OutputFormat::Plain => {
+                let (mut backlight, mut leds, mut displays) = (0, 0, 0);
                 for device in devices {
+                    match device.class {
+                        Class::Backlight => backlight += 1,
+                        Class::Leds => leds += 1,
+                        Class::Ddc => displays += 1,
+                    }
                     writeln!(output, "{MAGENTA}{}{R}", device.name)?;
                     writeln!(output, "    {CYAN}path:{R} {}", device.path.display())?;
                     writeln!(output, "    {CYAN}class:{R} {}", device.class)?;
                     writeln!(output, "    {CYAN}brightness: {R} {}", device.brightness)?;
                     writeln!(output, "    {CYAN}max brightness:{R} {}", device.max_brightness)?;
+                    writeln!(output, "    {CYAN}levels:{R} {}", device.levels())?;
+                    writeln!(output, "    {CYAN}percent:{R} {:.precision$}", percent(&device))?;
+                    if let Some(device_type) = &device.device_type {
+                        writeln!(output, "    {CYAN}type:{R} {device_type}")?;
+                    }
+                    if let Some(scale) = &device.scale {
+                        writeln!(output, "    {CYAN}scale:{R} {scale}")?;
+                    }
+                    if let Some(trigger) = &device.trigger {
+                        writeln!(output, "    {CYAN}trigger:{R} {trigger}")?;
+                    }
+                    if let Some(formula) = formula(&device) {
+                        writeln!(
+                            output,
+                            "    {CYAN}formula:{R} percent = 100 * log10(brightness) / {:.4}",
+                            formula.log10_max_brightness
+                        )?;
+                        writeln!(
+                            output,
+                            "    {CYAN}percent per 10x brightness:{R} {:.precision$}",
+                            formula.percent_per_decade
+                        )?;
+                    }
+                }
+                if summary {
+                    writeln!(output, "{}", device_summary(backlight, leds, displays))?;
                 }
             }
             OutputFormat::Json => {
-                #[derive(Serialize)]
-                struct Output {
-                    devices: Vec<DeviceOutput>,
-                }
-                let devices = devices.map(DeviceOutput::from).collect();
-                serde_json::to_writer(output, &Output { devices })?;
+                let devices = devices.map(output_of).collect();
+                serde_json::to_writer(output, &DevicesOutput { devices })?;
+            }
+            OutputFormat::CompactJson => {
+                let devices = devices.map(output_of).collect();
+                // Parsed from text instead of using `to_value`, which would write
+                // percentages with the noise of converting them to `f64`.
+                let json = serde_json::to_string(&DevicesOutput { devices })?;
+                let mut value: serde_json::Value = serde_json::from_str(&json)?;
+                strip_nulls(&mut value);
+                serde_json::to_writer(output, &value)?;
             }
             OutputFormat::JsonLines => {
                 for device in devices {
-                    let device = DeviceOutput::from(device);
-                    serde_json::to_writer(&mut output, &device)?;
+                    serde_json::to_writer(&mut output, &output_of(device))?;
+                    // Flush each line, so consumers can process them as they arrive.
+                    writeln!(output)?;
+                    output.flush()?;
                 }
             }
             OutputFormat::Csv => {
                 for device in devices {
                     writeln!(
                         output,
-                        "{BLUE}{}{R},{GREEN}{}{R},{YELLOW}{}{R},{CYAN}{}{R},{MAGENTA}{}{R}",
+                        "{BLUE}{}{R},{GREEN}{}{R},{YELLOW}{}{R},{CYAN}{}{R},{MAGENTA}{}{R},{BLUE}{:.precision$}{R},{GREEN}{}{R}",
                         device.name,
                         device.path.display(),
                         device.class,
                         device.brightness,
-                        device.max_brightness
+                        device.max_brightness,
+                        percent(&device),
+                        device.device_type.as_deref().unwrap_or_default(),
+                    )?;
+                }
+            }
+            OutputFormat::Tsv => {
+                for device in devices {
+                    writeln!(
+                        output,
+                        "{}\t{}\t{}\t{}\t{}\t{:.precision$}\t{}",
+                        device.name,
+                        device.path.display(),
+                        device.class,
+                        device.brightness,
+                        device.max_brightness,
+                        percent(&device),
+                        device.device_type.as_deref().unwrap_or_default(),
+                    )?;
+                }
+            }
+            OutputFormat::Pango => {
+                for device in devices {
+                    let percent = percent(&device);
+                    writeln!(
+                        output,
+                        "<span foreground='{}'>{}: {percent:.precision$}%</span>",
+                        pango_color(percent),
+                        pango_escape(&device.name),
                     )?;
                 }
             }
@@ -361,9 +2038,53 @@ impl OutputFormat {
 
 #[derive(Args)]
 struct InfoArgs {
-    /// Format to output device data
-    #[arg(short, long, value_enum, default_value_t)]
-    format: OutputFormat,
+    /// Format to output device data [default: plain]
+    #[arg(short, long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Order in which devices are listed
+    #[arg(long, value_enum, default_value_t)]
+    sort: SortKey,
+
+    /// List devices in the opposite order of `--sort`
+    #[arg(long)]
+    reverse: bool,
+
+    /// Do not print the number of devices found in plain format
+    #[arg(long)]
+    no_summary: bool,
+
+    /// Read the brightness reported by the hardware (`actual_brightness`) when available
+    #[arg(long)]
+    actual: bool,
+
+    /// Report the brightness as a plain percentage of the max brightness, instead of
+    /// adjusting it to human perception
+    #[arg(long)]
+    linear_percent: bool,
+
+    /// How percentages are computed from raw values [default: `linear` for LEDs,
+    /// `perceptual` for other devices]
+    #[arg(
+        long = "curve",
+        value_enum,
+        value_name = "CURVE",
+        conflicts_with = "linear_percent"
+    )]
+    scale: Option<Scale>,
+
+    /// Include the parameters used to convert raw values to percentages, to understand
+    /// the conversion for each device
+    #[arg(long)]
+    show_math: bool,
+
+    /// Write to a file instead of stdout, without colors
+    #[arg(short, long, value_name = "PATH", value_parser = validate_file_path)]
+    output: Option<PathBuf>,
+
+    /// Only print the number of matching devices, 0 if there are none
+    #[arg(long, conflicts_with_all = ["format", "output"])]
+    count: bool,
 
     #[command(flatten)]
     filters: FilterArgs,
@@ -381,25 +2102,155 @@ struct SaveArgs {
     /// Print the values that would be used without saving.
     #[arg(long)]
     print_defaults: bool,
+
+    /// Format used by `--print-defaults`
+    #[arg(long, value_enum, default_value_t, requires = "print_defaults")]
+    format: DefaultsFormat,
+
+    /// Record when the brightness was saved
+    #[arg(long)]
+    timestamp: bool,
+
+    /// Also save the brightness as a percentage, which is restored instead of the raw
+    /// value, so it still applies if the max brightness of the device changes
+    #[arg(long)]
+    as_percent: bool,
+}
+
+#[derive(Args)]
+struct DaemonArgs {
+    /// Path of the socket clients connect to [default: $XDG_RUNTIME_DIR/brighter.sock]
+    #[arg(short, long, value_parser = validate_file_path)]
+    socket: Option<PathBuf>,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+}
+
+fn get_socket_path(default: Option<PathBuf>) -> io::Result<PathBuf> {
+    default
+        .or_else(|| {
+            let path = env::var_os("XDG_RUNTIME_DIR");
+            log::info!("XDG_RUNTIME_DIR = {path:?}");
+            path.filter(|s| !s.is_empty())
+                .map(PathBuf::from)
+                .filter(|p| p.is_absolute())
+                .map(|p| p.join(format!("{BIN_NAME}.sock")))
+        })
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not determine a valid socket path")
+        })
+}
+
+#[derive(Copy, Clone, Default, ValueEnum)]
+enum InputFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Copy, Clone, Default, ValueEnum)]
+enum DefaultsFormat {
+    #[default]
+    Plain,
+    Json,
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Increment brightness by the given percentage.
+    #[command(mut_arg("value", |arg| arg.value_parser(Level::step_parser)))]
     Add(UpdateArgs),
     /// Decrease brightness by the given percentage.
+    #[command(mut_arg("value", |arg| arg.value_parser(Level::step_parser)))]
     Sub(UpdateArgs),
     /// Set brightness to the given percentage.
     Set(UpdateArgs),
+    /// Set brightness to a raw value, without any conversion.
+    SetRaw {
+        /// Raw brightness value, values above the max brightness are clamped
+        value: Brightness,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// Set brightness according to the position of the sun.
+    Auto(AutoArgs),
+    /// Set brightness depending on whether the system is on battery, using UPower.
+    #[command(name = "auto-power")]
+    Power(PowerArgs),
     /// Get current brightness as a percentage.
-    Get(FilterArgs),
+    Get(GetArgs),
     /// Get information about devices.
     Info(InfoArgs),
+    /// Activate a trigger of an LED (e.g. `kbd-capslock`), or deactivate it with `none`.
+    Trigger {
+        /// Name of the trigger, see the `trigger` file of the device for the available ones
+        name: String,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// Print the raw brightness each percentage converts to, to help choose a step size.
+    Calibrate(FilterArgs),
+    /// Print the path of the device the filters resolve to.
+    Which(FilterArgs),
     /// Save current device(s) brightness
     Save(SaveArgs),
+    /// Interactively adjust the brightness of devices.
+    Tui(FilterArgs),
+    /// Broadcast brightness changes as JSON lines to clients of a Unix socket.
+    Daemon(DaemonArgs),
+    /// Write a raw brightness value directly to sysfs, used by `--escalate`.
+    #[command(hide = true)]
+    WriteBrightness {
+        /// Full path to the device
+        path: PathBuf,
+        brightness: Brightness,
+    },
+    /// Run commands read from stdin, one per line (e.g. `add 5`), keeping the connection to
+    /// the system bus open between them.
+    Repl,
+    /// Print the version along with build information, for bug reports.
+    Version {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the JSON Schema of the output of `info --format json`.
+    #[command(hide = true)]
+    Schema,
     /// Restore brightness (inverse of `save` command)
     Restore {
-        /// Path to the file to read device state from
+        /// Path to the file to read device state from, can be given multiple times
+        #[arg(short, long, value_parser = validate_file_path)]
+        file: Vec<PathBuf>,
+
+        /// Do not modify any device, only show the changes that would be made.
+        #[arg(short, long)]
+        simulate: bool,
+
+        /// Format of the file to read device state from
+        #[arg(long, value_enum, default_value_t)]
+        input_format: InputFormat,
+
+        /// Fade each device to its saved brightness over this many milliseconds
+        #[arg(long, value_name = "MS")]
+        ramp: Option<u64>,
+
+        /// Restore the only device of the same class when a saved device no longer exists
+        /// (e.g. renamed by a driver update)
+        #[arg(long)]
+        remap: bool,
+
+        /// Directory containing the device classes, searched for saved devices that no
+        /// longer exist at their path [default: /sys/class]
+        #[arg(long, value_name = "PATH")]
+        sysfs_root: Option<PathBuf>,
+    },
+    /// Delete the saved device(s) brightness
+    Reset {
+        /// Path to the file with the saved device state
         #[arg(short, long, value_parser = validate_file_path)]
         file: Option<PathBuf>,
     },
@@ -416,6 +2267,63 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     verbose: u8,
 
+    /// Method used to write brightness values
+    #[arg(long, value_enum, default_value_t, global = true)]
+    write_method: WriteMethod,
+
+    /// Do not connect to the system bus, same as `--write-method sysfs`
+    #[arg(long, global = true, conflicts_with = "write_method")]
+    no_dbus: bool,
+
+    /// Retry writing through `pkexec` when permission to write to sysfs is denied
+    #[arg(long, global = true)]
+    escalate: bool,
+
+    /// Milliseconds to wait for the connection to the system bus
+    #[arg(
+        long,
+        global = true,
+        value_name = "MS",
+        default_value_t = ControllerOptions::DEFAULT_TIMEOUT.as_millis() as u64
+    )]
+    dbus_timeout: u64,
+
+    /// Milliseconds to wait for the command to finish before giving up, exiting with
+    /// status 124
+    #[arg(long, global = true, value_name = "MS")]
+    timeout: Option<u64>,
+
+    /// Seconds to wait for a matching device to appear before running the command (e.g.
+    /// when the driver is loaded late during boot)
+    #[arg(long, global = true, value_name = "SECONDS")]
+    wait_for_device: Option<u64>,
+
+    /// Times to retry a failed write (e.g. a device busy right after resume)
+    #[arg(long, global = true, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Show a desktop notification with the new brightness after `add`, `sub` or `set`
+    #[arg(long, global = true)]
+    notify: bool,
+
+    /// Format of log messages written to stderr
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_format: logger::LogFormat,
+
+    /// File with a table of `percent,raw` rows (or a JSON array of pairs) to map
+    /// percentages to raw brightness values, instead of the default curve
+    #[arg(long, global = true, value_name = "PATH")]
+    curve_file: Option<PathBuf>,
+
+    /// Number of decimals of printed percentages, up to 6
+    #[arg(long, global = true, value_name = "N", default_value_t = DEFAULT_PRECISION)]
+    precision: usize,
+
+    /// Use a comma as the decimal separator in the percentage printed by `get`, `add`,
+    /// `sub` and `set`
+    #[arg(long, global = true)]
+    decimal_comma: bool,
+
     #[command(flatten)]
     color: colorchoice_clap::Color,
 }
@@ -430,78 +2338,389 @@ impl Cli {
         }
     }
 
-    fn run(self) -> Result<ExitCode, Box<dyn Error>> {
-        match self.command {
-            Command::Add(args) => update_brightness(args, UpdateAction::Add)?,
-            Command::Sub(args) => update_brightness(args, UpdateAction::Sub)?,
-            Command::Set(args) => update_brightness(args, UpdateAction::Set)?,
-            Command::Get(filters) => {
-                let device = device::get_device(&filters.into())?;
-                let percent = brightness_to_percent(device.brightness, device.max_brightness);
-                writeln!(io::stdout(), "{percent:.2}")?;
+    fn percent_format(&self) -> PercentFormat {
+        PercentFormat {
+            precision: self.precision.min(MAX_PRECISION),
+            decimal_comma: self.decimal_comma,
+        }
+    }
+
+    fn controller_options(&self) -> ControllerOptions {
+        ControllerOptions {
+            method: if self.no_dbus {
+                WriteMethod::Sysfs
+            } else {
+                self.write_method
+            },
+            escalate: self.escalate,
+            timeout: Duration::from_millis(self.dbus_timeout),
+            notify: self.notify,
+            retries: self.retries,
+        }
+    }
+
+    /// Fill arguments not given in the command line with values from the configuration.
+    fn apply_config(&mut self, config: &Config) -> Result<(), String> {
+        if let Command::Add(args) | Command::Sub(args) = &mut self.command
+            && args.value.is_none()
+        {
+            args.value = Some(Level::Percent(default_step(config)?));
+        }
+        if let Command::Info(args) = &mut self.command {
+            args.format = args.format.or(config.default_format);
+        }
+        // Only use the default class if no filters were provided, since the given device
+        // name may not belong to that class.
+        if let Some(filters) = self.command.filters_mut()
+            && filters.class.is_empty()
+            && filters.device.is_none()
+            && filters.device_contains.is_none()
+        {
+            filters
+                .class
+                .extend(config.default_class.map(ClassFilter::from));
+        }
+        Ok(())
+    }
+
+    fn run(mut self) -> Result<ExitCode, Box<dyn Error>> {
+        let config = Config::load()?;
+        self.apply_config(&config)?;
+        let controller = LazyController::new(self.controller_options());
+        let format = self.percent_format();
+        let table = self
+            .curve_file
+            .as_deref()
+            .map(LookupTable::load)
+            .transpose()?;
+        if let Command::Repl = self.command {
+            let input = io::stdin().lock();
+            return run_batch(input, &config, &controller, format, table.as_ref());
+        }
+        if let Some(seconds) = self.wait_for_device
+            && let Some(filters) = self.command.filters_mut()
+        {
+            let filters = device::DeviceFilters::from(&*filters);
+            device::wait_for_device(&filters, Duration::from_secs(seconds))?;
+        }
+        self.command.execute(&controller, format, table.as_ref())
+    }
+}
+
+/// Run commands read line by line from `input`, sharing the same `controller` so the
+/// connection to the system bus is reused. Global options are taken from the invocation
+/// of `repl`, the ones given in a line are ignored.
+fn run_batch(
+    input: impl BufRead,
+    config: &Config,
+    controller: &LazyController,
+    format: PercentFormat,
+    table: Option<&LookupTable>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mut failed = false;
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = Cli::try_parse_from(iter::once(BIN_NAME).chain(line.split_whitespace()))
+            .map_err(|err| {
+                // Only the first line, without the usage.
+                let msg = err.to_string();
+                let msg = msg.lines().next().unwrap_or_default();
+                msg.strip_prefix("error: ")
+                    .unwrap_or(msg)
+                    .to_string()
+                    .into()
+            })
+            .and_then(|mut cli| {
+                cli.apply_config(config)?;
+                cli.command.execute(controller, format, table)
+            });
+        match result {
+            Ok(code) if code == ExitCode::SUCCESS => {}
+            Ok(_) => failed = true,
+            Err(err) => {
+                failed = true;
+                log::error!("{line:?}: {err}");
+            }
+        }
+    }
+    Ok(if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+impl Command {
+    /// Filters of commands that use devices.
+    fn filters_mut(&mut self) -> Option<&mut FilterArgs> {
+        match self {
+            Command::Add(args) | Command::Sub(args) | Command::Set(args) => Some(&mut args.filters),
+            Command::SetRaw { filters, .. } => Some(filters),
+            Command::Auto(args) => Some(&mut args.filters),
+            Command::Power(args) => Some(&mut args.filters),
+            Command::Get(args) => Some(&mut args.filters),
+            Command::Which(filters) => Some(filters),
+            Command::Trigger { filters, .. } => Some(filters),
+            Command::Calibrate(filters) => Some(filters),
+            Command::Info(args) => Some(&mut args.filters),
+            Command::Save(args) => Some(&mut args.filters),
+            Command::Tui(filters) => Some(filters),
+            Command::Daemon(args) => Some(&mut args.filters),
+            Command::WriteBrightness { .. }
+            | Command::Version { .. }
+            | Command::Repl
+            | Command::Schema
+            | Command::Restore { .. }
+            | Command::Reset { .. } => None,
+        }
+    }
+
+    /// Run the command. Only commands that write brightness may use the `controller`.
+    /// Percentages are printed with `format`, and converted to brightness values with
+    /// `table` if given.
+    fn execute(
+        self,
+        controller: &LazyController,
+        format: PercentFormat,
+        table: Option<&LookupTable>,
+    ) -> Result<ExitCode, Box<dyn Error>> {
+        let precision = format.precision;
+        let cache = DeviceCache::default();
+        match self {
+            Command::Add(args) => {
+                if !update_brightness(args, UpdateAction::Add, controller, &cache, format, table)? {
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+            Command::Sub(args) => {
+                if !update_brightness(args, UpdateAction::Sub, controller, &cache, format, table)? {
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+            Command::Set(mut args) => {
+                // Relative values behave like `add` and `sub`.
+                let action = match args.value {
+                    Some(Level::Relative(action, percent)) => {
+                        args.value = Some(Level::Percent(percent));
+                        action
+                    }
+                    _ => UpdateAction::Set,
+                };
+                if !update_brightness(args, action, controller, &cache, format, table)? {
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+            Command::SetRaw { value, filters } => {
+                let mut device = device::get_device(&filters.into(), &cache)?;
+                controller.get()?.set_brightness(&mut device, value)?;
+                let percent =
+                    Scale::of_class(device.class).percent(device.brightness, device.max_brightness);
+                writeln!(io::stdout(), "{}", format.format(percent))?;
+            }
+            Command::Auto(args) => {
+                let args = UpdateArgs::set(args.percent()?, args.simulate, args.filters);
+                if !update_brightness(args, UpdateAction::Set, controller, &cache, format, table)? {
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+            Command::Power(args) => {
+                let percent = if controller.on_battery()? {
+                    args.on_battery
+                } else {
+                    args.on_ac
+                };
+                let args = UpdateArgs::set(percent, args.simulate, args.filters);
+                if !update_brightness(args, UpdateAction::Set, controller, &cache, format, table)? {
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+            Command::Get(args) => {
+                let percent = args.aggregate.get_percent(
+                    &args.filters.into(),
+                    args.actual,
+                    args.invert,
+                    Scale::from_args(args.scale, args.linear_percent),
+                    &cache,
+                )?;
+                if args.bar {
+                    let bar = progress_bar(percent, args.bar_width);
+                    writeln!(io::stdout(), "{bar} {}%", format.format(percent))?;
+                } else {
+                    args.format.write_percent(io::stdout(), percent, format)?;
+                }
+            }
+            Command::Info(args) if args.count => {
+                let count = match device::get_devices(&args.filters.into(), &cache) {
+                    Ok(devices) => devices.count(),
+                    Err(device::FetchError::NotFound(_)) => 0,
+                    Err(err) => return Err(err.into()),
+                };
+                writeln!(io::stdout(), "{count}")?;
             }
             Command::Info(args) => {
                 let filters = args.filters.into();
-                let devices = device::get_devices(&filters)?;
-                let ouput = anstream::stdout().lock();
-                args.format.write(ouput, devices)?;
+                let mut devices: Vec<_> = device::get_devices(&filters, &cache)?.collect();
+                if args.actual {
+                    for device in &mut devices {
+                        device.read_actual_brightness()?;
+                    }
+                }
+                device::sort_devices(&mut devices, args.sort, args.reverse);
+                let format = args.format.unwrap_or_default();
+                let options = WriteOptions {
+                    summary: !args.no_summary,
+                    precision,
+                    scale: Scale::from_args(args.scale, args.linear_percent),
+                    no_color: args.output.is_some(),
+                    show_math: args.show_math,
+                };
+                match args.output {
+                    Some(path) => {
+                        let file = fs::File::create(&path)
+                            .map_err(|err| device::PathError::new(err, &path))?;
+                        format.write(file, devices.into_iter(), options)?;
+                    }
+                    None => format.write(io::stdout().lock(), devices.into_iter(), options)?,
+                }
             }
             Command::Save(mut args) => {
                 // Save all backlight devices by default if no filters were provided,
                 // on the belief that this would be the common usage.
-                if args.filters.class.is_none() && args.filters.device.is_none() {
-                    args.filters.class = Some(Class::Backlight);
+                if args.filters.class.is_empty()
+                    && args.filters.device.is_none()
+                    && args.filters.device_contains.is_none()
+                {
+                    args.filters.class = vec![ClassFilter::Backlight];
                 }
 
                 let file_path = get_save_path(args.file)?;
                 let filters = args.filters.into();
-                let devices = device::get_devices(&filters)?;
+                let devices = device::get_devices(&filters, &cache)?;
 
                 if args.print_defaults {
-                    let devices = devices.map(|dev| dev.name).collect::<Vec<_>>().join(", ");
+                    let devices = devices.map(|dev| dev.name).collect::<Vec<_>>();
                     let mut stdout = io::stdout();
-                    writeln!(stdout, "file = {}", file_path.display())?;
-                    writeln!(stdout, "device(s) = {devices}")?;
+                    match args.format {
+                        DefaultsFormat::Plain => {
+                            writeln!(stdout, "file = {}", file_path.display())?;
+                            writeln!(stdout, "device(s) = {}", devices.join(", "))?;
+                        }
+                        DefaultsFormat::Json => {
+                            #[derive(Serialize)]
+                            struct Output {
+                                file: PathBuf,
+                                devices: Vec<String>,
+                            }
+                            let output = Output {
+                                file: file_path,
+                                devices,
+                            };
+                            serde_json::to_writer(&mut stdout, &output)?;
+                            writeln!(stdout)?;
+                        }
+                    }
                     return Ok(ExitCode::SUCCESS);
                 }
 
-                let data: Vec<_> = devices.map(SaveData::from).collect();
+                let saved_at = args.timestamp.then(|| rfc3339(SystemTime::now()));
+                let data: Vec<_> = devices
+                    .map(|device| SaveData {
+                        saved_at: saved_at.clone(),
+                        percent: args.as_percent.then(|| {
+                            Scale::of_class(device.class)
+                                .percent(device.brightness, device.max_brightness)
+                        }),
+                        ..SaveData::from(device)
+                    })
+                    .collect();
                 if let Some(prefix) = file_path.parent() {
                     fs::create_dir_all(prefix)?;
                 }
-                fs::write(file_path, serde_json::to_string_pretty(&data)?)?;
+                write_atomic(&file_path, serde_json::to_string_pretty(&data)?.as_bytes())?;
             }
-            Command::Restore { file } => {
-                let content = fs::read(get_save_path(file)?)?;
-                let save_data: Vec<SaveData> = serde_json::from_slice(&content)?;
-
-                if save_data.is_empty() {
-                    log::warn!("no devices to restore");
-                    return Ok(ExitCode::SUCCESS);
+            Command::Which(filters) => {
+                let device = device::get_device(&filters.into(), &cache)?;
+                writeln!(io::stdout(), "{}", device.path.display())?;
+            }
+            Command::Calibrate(filters) => {
+                let device = device::get_device(&filters.into(), &cache)?;
+                let curve = Curve {
+                    table,
+                    scale: Scale::of_class(device.class),
+                    ..Curve::default()
+                };
+                let output = anstream::stdout().lock();
+                write_calibration(output, device.max_brightness, curve)?;
+            }
+            Command::Trigger { name, filters } => {
+                let mut filters: device::DeviceFilters = filters.into();
+                // Only LEDs have triggers.
+                if filters.classes.is_empty() {
+                    filters.classes.push(Class::Leds);
                 }
-
-                let controller = device::Controller::new();
+                let mut device = device::get_device(&filters, &cache)?;
+                device.set_trigger(&name)?;
+                log::info!(r#"activated trigger "{name}" of device "{}""#, device.name);
+            }
+            Command::Tui(filters) => {
+                let filters = filters.into();
+                let devices = device::get_devices(&filters, &cache)?.collect();
+                tui::run(devices, controller.get()?)?;
+            }
+            Command::Daemon(args) => {
+                let socket = get_socket_path(args.socket)?;
+                daemon::run(&socket, args.filters.into())?;
+            }
+            Command::WriteBrightness { path, brightness } => {
+                let mut device = Device::from_path(path)?;
+                let options = ControllerOptions {
+                    method: WriteMethod::Sysfs,
+                    ..Default::default()
+                };
+                device::Controller::new(options)?.set_brightness(&mut device, brightness)?;
+            }
+            Command::Repl => return Err("repl can't be used inside itself".into()),
+            Command::Version { json } => {
+                let version = VersionOutput::CURRENT;
+                let mut stdout = io::stdout().lock();
+                if json {
+                    serde_json::to_writer(&mut stdout, &version)?;
+                    writeln!(stdout)?;
+                } else {
+                    writeln!(stdout, "{BIN_NAME} {}", version.version)?;
+                    writeln!(stdout, "git: {}", or_unknown(version.git))?;
+                    writeln!(stdout, "rustc: {}", or_unknown(version.rustc))?;
+                }
+            }
+            Command::Schema => {
+                let schema = schemars::schema_for!(DevicesOutput);
+                let mut stdout = io::stdout().lock();
+                serde_json::to_writer_pretty(&mut stdout, &schema)?;
+                writeln!(stdout)?;
+            }
+            Command::Restore {
+                file,
+                simulate,
+                input_format,
+                ramp,
+                remap,
+                sysfs_root,
+            } => {
+                let paths = if file.is_empty() {
+                    vec![get_save_path(None)?]
+                } else {
+                    file
+                };
                 let mut fail_to_restore = false;
-
-                // Explicitly handle all errors to allow restoring as much devices as possible.
-                for data in save_data {
-                    match Device::from_path(data.path) {
-                        Ok(mut device) => {
-                            let res = controller.set_brightness(&mut device, data.brightness);
-                            if let Err(err) = res {
-                                fail_to_restore = true;
-                                log::error!(
-                                    r#"failed to set brightness for device "{}": {err}"#,
-                                    device.name
-                                );
-                            } else {
-                                log::info!(
-                                    r#"restored device "{}" with brightness: {}"#,
-                                    device.name,
-                                    device.brightness
-                                );
-                            }
-                        }
+                let mut save_data = Vec::new();
+                // A file that can't be read doesn't stop the others from being restored.
+                for path in paths {
+                    match read_save_data(&path, input_format, &mut fail_to_restore) {
+                        Ok(data) => save_data.extend(data),
                         Err(err) => {
                             fail_to_restore = true;
                             log::error!("{err}");
@@ -509,10 +2728,38 @@ impl Cli {
                     }
                 }
 
-                if fail_to_restore {
+                if save_data.is_empty() {
+                    log::warn!("no devices to restore");
+                    return Ok(if fail_to_restore {
+                        ExitCode::FAILURE
+                    } else {
+                        ExitCode::SUCCESS
+                    });
+                }
+
+                let backend = if simulate {
+                    None
+                } else {
+                    Some(controller as &dyn BrightnessBackend)
+                };
+                let ramp = ramp.map(Duration::from_millis);
+                let sysfs_root = sysfs_root.as_deref();
+                if !restore(save_data, backend, precision, ramp, remap, sysfs_root, &cache)?
+                    || fail_to_restore
+                {
                     return Ok(ExitCode::FAILURE);
                 }
             }
+            Command::Reset { file } => {
+                let path = get_save_path(file)?;
+                match fs::remove_file(&path) {
+                    Ok(()) => writeln!(io::stdout(), "removed {}", path.display())?,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        log::info!("nothing to remove at {}", path.display());
+                    }
+                    Err(err) => return Err(device::PathError::new(err, path).into()),
+                }
+            }
         }
 
         Ok(ExitCode::SUCCESS)
@@ -520,13 +2767,40 @@ impl Cli {
 }
 
 fn main() -> ExitCode {
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
+
     let cli = Cli::parse();
     cli.color.write_global();
 
-    log::set_logger(&logger::Logger).expect("setting logger");
+    let logger = logger::Logger {
+        format: cli.log_format,
+    };
+    log::set_logger(Box::leak(Box::new(logger))).expect("setting logger");
     log::set_max_level(cli.log_level());
 
-    match cli.run() {
+    let Some(timeout) = cli.timeout.map(Duration::from_millis) else {
+        return exit_code(cli.run());
+    };
+    // A command stuck on a read can't be interrupted, so it runs on a thread that's left
+    // behind if it doesn't finish in time.
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || _ = sender.send(exit_code(cli.run())));
+    match receiver.recv_timeout(timeout) {
+        Ok(code) => code,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            log::error!("timed out after {}ms", timeout.as_millis());
+            ExitCode::from(TIMEOUT_EXIT_CODE)
+        }
+        // The command panicked, the message was already printed.
+        Err(mpsc::RecvTimeoutError::Disconnected) => ExitCode::FAILURE,
+    }
+}
+
+/// Exit status when `--timeout` expires, same as `timeout(1)`.
+const TIMEOUT_EXIT_CODE: u8 = 124;
+
+fn exit_code(result: Result<ExitCode, Box<dyn Error>>) -> ExitCode {
+    match result {
         Ok(code) => code,
         Err(err) => {
             if let Some(ioerr) = err.downcast_ref::<io::Error>()
@@ -542,30 +2816,753 @@ fn main() -> ExitCode {
 
 #[cfg(test)]
 mod test {
+    use std::cell::RefCell;
+
     use super::*;
-    use clap::CommandFactory;
+
+    /// Records the values written and the notifications sent instead of touching any
+    /// device.
+    #[derive(Default)]
+    struct MockBackend(RefCell<Vec<(String, Brightness)>>, RefCell<Vec<Percent>>);
+
+    impl BrightnessBackend for MockBackend {
+        fn set(
+            &self,
+            device: &mut Device,
+            value: Brightness,
+        ) -> Result<device::controller::Backend, device::controller::Error> {
+            device.brightness = value.min(device.max_brightness);
+            self.0.borrow_mut().push((device.name.clone(), value));
+            Ok(device::controller::Backend::Sysfs)
+        }
+
+        fn set_multi_intensity(
+            &self,
+            device: &mut Device,
+            values: Vec<Brightness>,
+        ) -> Result<(), device::controller::Error> {
+            device.multi_intensity = Some(values);
+            Ok(())
+        }
+
+        fn notify(&self, percent: Percent) -> Result<(), device::controller::Error> {
+            self.1.borrow_mut().push(percent);
+            Ok(())
+        }
+    }
+
+    fn device(brightness: Brightness, max_brightness: Brightness) -> Device {
+        Device {
+            name: "device".to_string(),
+            path: PathBuf::from("/sys/class/backlight/device"),
+            class: Class::Backlight,
+            brightness,
+            max_brightness,
+            multi_intensity: None,
+            device_type: None,
+            scale: None,
+            trigger: None,
+        }
+    }
+
+    fn update_args(args: &[&str]) -> UpdateArgs {
+        let cli = Cli::try_parse_from([BIN_NAME].iter().chain(args)).unwrap();
+        match cli.command {
+            Command::Add(args) | Command::Sub(args) | Command::Set(args) => args,
+            _ => unreachable!(),
+        }
+    }
 
     #[test]
     fn test_cli() {
         Cli::command().debug_assert();
     }
 
+    #[test]
+    fn test_run_batch() {
+        let config = Config::default();
+        let controller = LazyController::new(ControllerOptions::default());
+        let format = PercentFormat {
+            precision: DEFAULT_PRECISION,
+            decimal_comma: false,
+        };
+        let input = "# comment\n\n  version --json\n";
+        let code = run_batch(input.as_bytes(), &config, &controller, format, None).unwrap();
+        assert_eq!(code, ExitCode::SUCCESS);
+        let input = "version\nunknown-command\nrepl\n";
+        let code = run_batch(input.as_bytes(), &config, &controller, format, None).unwrap();
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(!controller.is_initialized());
+    }
+
+    #[test]
+    fn test_keyboard_filter() {
+        let filters =
+            device::DeviceFilters::from(update_args(&["set", "--keyboard", "50%"]).filters);
+        assert_eq!(filters.classes, [Class::Leds]);
+        assert_eq!(filters.device_contains.as_deref(), Some("kbd_backlight"));
+        let args = [BIN_NAME, "get", "--keyboard", "--class", "leds"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_read_commands_dont_connect() {
+        let commands: &[&[&str]] = &[
+            &["get"],
+            &["get", "--aggregate", "avg"],
+            &["which", "--class", "backlight"],
+            &["info", "--format", "json"],
+            &["info", "--count"],
+            &["save", "--print-defaults"],
+            &["set", "50", "--simulate"],
+            &["add", "5", "--simulate"],
+            &["version", "--json"],
+            &["calibrate"],
+        ];
+        for args in commands {
+            let cli = Cli::try_parse_from([BIN_NAME].iter().chain(*args)).unwrap();
+            let controller = LazyController::new(ControllerOptions::default());
+            // The result depends on the devices available, only the controller matters.
+            let format = cli.percent_format();
+            _ = cli.command.execute(&controller, format, None);
+            assert!(!controller.is_initialized(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_level_parser() {
+        let percent = |p| Level::Percent(Percent::new(p).unwrap());
+        assert_eq!(Level::clap_parser("50%"), Ok(percent(50.0)));
+        assert_eq!(Level::clap_parser("10.5%"), Ok(percent(10.5)));
+        assert_eq!(Level::clap_parser("800"), Ok(Level::Raw(800)));
+        assert!(Level::clap_parser("101%").is_err());
+        assert!(Level::clap_parser("%").is_err());
+        // Only `set` takes raw values, so decimals need a suffix
+        assert!(Level::clap_parser("10.5").is_err());
+        assert_eq!(Level::step_parser("2.5"), Ok(percent(2.5)));
+        assert_eq!(Level::step_parser("2.5%"), Ok(percent(2.5)));
+        assert!(Level::step_parser("101").is_err());
+        let percent_of = |args: &[&str]| update_args(args).value;
+        assert_eq!(percent_of(&["add", "2.5"]), Some(percent(2.5)));
+        assert_eq!(percent_of(&["sub", "10"]), Some(percent(10.0)));
+        assert!(Cli::try_parse_from([BIN_NAME, "set", "2.5"]).is_err());
+
+        // Relative values are always percentages
+        let relative = |action, p| Level::Relative(action, Percent::new(p).unwrap());
+        assert_eq!(Level::clap_parser("+10"), Ok(relative(UpdateAction::Add, 10.0)));
+        assert_eq!(Level::clap_parser("-1"), Ok(relative(UpdateAction::Sub, 1.0)));
+        assert_eq!(Level::clap_parser("-2.5%"), Ok(relative(UpdateAction::Sub, 2.5)));
+        assert!(Level::clap_parser("+101").is_err());
+        assert!(Level::clap_parser("--1").is_err());
+
+        assert_eq!(Level::Raw(5).step(), Ok(Percent::new(5.0).unwrap()));
+        assert!(Level::Raw(200).step().is_err());
+        assert!(relative(UpdateAction::Add, 5.0).step().is_err());
+    }
+
+    #[test]
+    fn test_update_device() {
+        let backend = MockBackend::default();
+        let update = |device: &mut Device, args: &[&str], action| {
+            update_device(device, &update_args(args), action, &backend, None)
+                .unwrap()
+                .brightness
+        };
+
+        // Clamped to the limits
+        let mut dev = device(98, 100);
+        assert_eq!(update(&mut dev, &["add", "5"], UpdateAction::Add), 100);
+        assert_eq!(dev.brightness, 100);
+        let mut dev = device(1, 100);
+        assert_eq!(update(&mut dev, &["sub", "5"], UpdateAction::Sub), 0);
+        let args = ["set", "0", "--no-zero-backlight"];
+        assert_eq!(update(&mut dev, &args, UpdateAction::Set), 1);
+
+        // Inverted devices are written the opposite value
+        let mut dev = device(10, 10);
+        assert_eq!(update(&mut dev, &["set", "100%", "--invert"], UpdateAction::Set), 10);
+        assert_eq!(dev.brightness, 0);
+        assert_eq!(update(&mut dev, &["sub", "100", "--invert"], UpdateAction::Sub), 0);
+        assert_eq!(dev.brightness, 10);
+
+        // LEDs use a linear curve unless given one
+        let mut dev = Device {
+            class: Class::Leds,
+            ..device(0, 100)
+        };
+        assert_eq!(update(&mut dev, &["set", "50%"], UpdateAction::Set), 50);
+        let args = ["set", "50%", "--curve", "perceptual"];
+        assert_eq!(update(&mut dev, &args, UpdateAction::Set), 10);
+
+        // A small step still changes a device with few levels
+        let mut dev = device(3, 7);
+        assert_eq!(update(&mut dev, &["add", "1"], UpdateAction::Add), 4);
+        assert_eq!(update(&mut dev, &["sub", "1"], UpdateAction::Sub), 3);
+
+        // Explicit rounding
+        let mut dev = device(0, 7);
+        assert_eq!(update(&mut dev, &["set", "50%"], UpdateAction::Set), 3);
+        let args = ["set", "50%", "--round", "down"];
+        assert_eq!(update(&mut dev, &args, UpdateAction::Set), 2);
+
+        // Raw values are clamped, not converted
+        assert_eq!(update(&mut dev, &["set", "5"], UpdateAction::Set), 5);
+        assert_eq!(update(&mut dev, &["set", "800"], UpdateAction::Set), 7);
+
+        // Hardware levels
+        assert_eq!(update(&mut dev, &["set", "--level", "2"], UpdateAction::Set), 2);
+        let args = update_args(&["set", "--level", "8"]);
+        assert!(update_device(&mut dev, &args, UpdateAction::Set, &backend, None).is_err());
+        let args = update_args(&["add", "--level", "1"]);
+        assert!(update_device(&mut dev, &args, UpdateAction::Add, &backend, None).is_err());
+
+        assert_eq!(backend.0.borrow().len(), 14);
+
+        // Nothing is written if the brightness doesn't change
+        let mut dev = device(50, 100);
+        let args = ["set", "50", "--if-changed"];
+        assert_eq!(update(&mut dev, &args, UpdateAction::Set), 50);
+        assert_eq!(backend.0.borrow().len(), 14);
+        let args = ["set", "60", "--if-changed"];
+        assert_eq!(update(&mut dev, &args, UpdateAction::Set), 60);
+        assert_eq!(backend.0.borrow().len(), 15);
+
+        // Nothing is written when simulating
+        let backend = MockBackend::default();
+        let mut dev = device(50, 100);
+        let args = update_args(&["set", "100%", "--simulate"]);
+        assert_eq!(
+            update_device(&mut dev, &args, UpdateAction::Set, &backend, None)
+                .unwrap()
+                .brightness,
+            100
+        );
+        assert_eq!(dev.brightness, 50);
+        assert!(backend.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_preserve_ratio_step() {
+        use assert_float_eq::assert_float_absolute_eq;
+
+        let devices = [device(25, 100), device(100, 100)];
+        let curve = Curve::default();
+        let average = (curve.percent(25, 100).get() + curve.percent(100, 100).get()) / 2.0;
+
+        let args = update_args(&["set", "100%", "--all", "--preserve-ratio"]);
+        let (action, step) = preserve_ratio_step(&devices, &args, UpdateAction::Set, None).unwrap();
+        assert_eq!(action, UpdateAction::Add);
+        assert_float_absolute_eq!(step.get(), 100.0 - average, 1e-4);
+
+        let args = update_args(&["set", "0%", "--all", "--preserve-ratio"]);
+        let (action, step) = preserve_ratio_step(&devices, &args, UpdateAction::Set, None).unwrap();
+        assert_eq!(action, UpdateAction::Sub);
+        assert_float_absolute_eq!(step.get(), average, 1e-4);
+
+        let args = update_args(&["set", "50", "--all", "--preserve-ratio"]);
+        assert!(preserve_ratio_step(&devices, &args, UpdateAction::Set, None).is_err());
+        let args = update_args(&["add", "5", "--all", "--preserve-ratio"]);
+        assert!(preserve_ratio_step(&devices, &args, UpdateAction::Add, None).is_err());
+    }
+
+    #[test]
+    fn test_fade() {
+        assert_eq!(Ease::Linear.apply(0.5), 0.5);
+        assert_eq!(Ease::EaseIn.apply(0.5), 0.25);
+        assert_eq!(Ease::EaseOut.apply(0.5), 0.75);
+        assert_eq!(Ease::EaseOut.apply(1.0), 1.0);
+
+        let backend = MockBackend::default();
+        let mut dev = device(0, 100);
+        let args = update_args(&["set", "100%", "--duration", "100"]);
+        update_device(&mut dev, &args, UpdateAction::Set, &backend, None).unwrap();
+        let values: Vec<_> = backend.0.borrow().iter().map(|(_, value)| *value).collect();
+        assert_eq!(values.len(), 5);
+        assert!(values.is_sorted(), "{values:?}");
+        assert_eq!(values.last(), Some(&100));
+        assert_eq!(dev.brightness, 100);
+
+        // Steps are written inverted too
+        let backend = MockBackend::default();
+        let mut dev = device(100, 100);
+        let args = update_args(&["set", "100%", "--duration", "40", "--invert"]);
+        update_device(&mut dev, &args, UpdateAction::Set, &backend, None).unwrap();
+        let values: Vec<_> = backend.0.borrow().iter().map(|(_, value)| *value).collect();
+        assert_eq!(values.last(), Some(&0));
+        assert!(values.is_sorted_by(|a, b| a >= b), "{values:?}");
+    }
+
+    #[test]
+    fn test_resolve_brightness() {
+        let percent = |p| Percent::new(p).unwrap();
+        let resolved =
+            resolve_brightness(0, 7, UpdateAction::Set, percent(50.0), None, Curve::default());
+        assert_eq!(resolved.brightness, 3);
+        assert_eq!(resolved.requested, percent(50.0));
+        assert_eq!(resolved.percent, brightness_to_percent(3, 7));
+        assert_ne!(resolved.percent, resolved.requested);
+
+        // Moves at least one unit
+        let resolved =
+            resolve_brightness(3, 7, UpdateAction::Add, percent(1.0), None, Curve::default());
+        assert_eq!(resolved.brightness, 4);
+    }
+
+    #[test]
+    fn test_restore() {
+        let cache = DeviceCache::default();
+        let root = device::test::mock_root("restore");
+        let save_data = || {
+            vec![
+                SaveData {
+                    path: device::test::mock_device(&root, "restore", 1, 10),
+                    brightness: 7,
+                    multi_intensity: None,
+                    class: None,
+                    max_brightness: None,
+                    saved_at: None,
+                    percent: None,
+                },
+                SaveData {
+                    path: PathBuf::from("/nonexistent/device"),
+                    brightness: 1,
+                    multi_intensity: None,
+                    class: None,
+                    max_brightness: None,
+                    saved_at: None,
+                    percent: None,
+                },
+            ]
+        };
+
+        let backend = MockBackend::default();
+        assert!(
+            !restore(save_data(), Some(&backend), DEFAULT_PRECISION, None, false, None, &cache)
+                .unwrap()
+        );
+        assert_eq!(*backend.0.borrow(), [("restore".to_string(), 7)]);
+
+        let backend = MockBackend::default();
+        restore(save_data(), None, DEFAULT_PRECISION, None, false, None, &cache).unwrap();
+        assert!(backend.0.borrow().is_empty());
+
+        let backend = MockBackend::default();
+        let ramp = Some(Duration::from_millis(60));
+        restore(save_data(), Some(&backend), DEFAULT_PRECISION, ramp, false, None, &cache).unwrap();
+        let writes = backend.0.borrow();
+        assert!(writes.len() > 1);
+        assert!(writes.is_sorted_by_key(|(_, brightness)| *brightness));
+        assert_eq!(writes.last(), Some(&("restore".to_string(), 7)));
+    }
+
+    #[test]
+    fn test_update_brightness() {
+        let root = device::test::mock_root("update_brightness");
+        device::test::mock_class_device(&root, Class::Backlight, "first", 0, 100);
+        device::test::mock_class_device(&root, Class::Backlight, "second", 100, 100);
+        let args = || {
+            let root = root.to_str().unwrap();
+            let filters = ["--class", "backlight", "--sysfs-root", root];
+            update_args(&[&["set", "40", "--all", "--no-lock"], &filters[..]].concat())
+        };
+        // Each update is a new command, with its own cache.
+        let update = |args| {
+            let backend = MockBackend::default();
+            let format = PercentFormat {
+                precision: 0,
+                decimal_comma: false,
+            };
+            let cache = DeviceCache::default();
+            let updated =
+                update_brightness(args, UpdateAction::Set, &backend, &cache, format, None);
+            (updated.unwrap(), backend.0.take(), backend.1.take())
+        };
+
+        let (updated, writes, notifications) = update(args());
+        assert!(updated);
+        assert_eq!(writes, [("first".to_string(), 40), ("second".to_string(), 40)]);
+        // A single notification with the average of all devices
+        assert_eq!(notifications, [Scale::Perceptual.percent(40, 100)]);
+
+        let mut simulated = args();
+        simulated.simulate = true;
+        assert_eq!(update(simulated), (true, vec![], vec![]));
+
+        // Other devices are still updated when one fails, level 50 is out of its range
+        device::test::mock_class_device(&root, Class::Backlight, "third", 0, 10);
+        let mut args = args();
+        (args.value, args.level) = (None, Some(50));
+        let (updated, writes, notifications) = update(args);
+        assert!(!updated);
+        assert_eq!(writes, [("first".to_string(), 50), ("second".to_string(), 50)]);
+        assert_eq!(notifications, [Scale::Perceptual.percent(50, 100)]);
+    }
+
+    #[test]
+    fn test_restore_renamed_device() {
+        let root = device::test::mock_root("renamed");
+        let sysfs_root = root.join("sysfs");
+        device::test::mock_class_device(&sysfs_root, Class::Backlight, "amdgpu_bl1", 0, 200);
+        device::test::mock_class_device(&sysfs_root, Class::Leds, "kbd_backlight", 0, 100);
+
+        let restore = |path: &str, class, remap| {
+            let save_data = vec![SaveData {
+                path: root.join(path),
+                brightness: 50,
+                multi_intensity: None,
+                class,
+                max_brightness: Some(100),
+                saved_at: None,
+                percent: None,
+            }];
+            let backend = MockBackend::default();
+            let cache = DeviceCache::default();
+            let restored =
+                restore(save_data, Some(&backend), 2, None, remap, Some(&sysfs_root), &cache);
+            (restored.unwrap(), backend.0.take())
+        };
+        // A device with the same name is found in the sysfs root
+        let restored = restore("old/leds/kbd_backlight", Some(Class::Leds), false);
+        assert_eq!(restored, (true, vec![("kbd_backlight".to_string(), 50)]));
+
+        let path = "old/backlight/amdgpu_bl0";
+        let class = Some(Class::Backlight);
+        assert_eq!(restore(path, class, false), (false, vec![]));
+        assert_eq!(restore(path, class, true), (true, vec![("amdgpu_bl1".to_string(), 90)]));
+        // Without the class there's nothing to search for.
+        assert_eq!(restore(path, None, true), (false, vec![]));
+    }
+
+    #[test]
+    fn test_saved_brightness() {
+        let device = device(0, 200);
+        let data = |percent: Option<f32>, max_brightness| SaveData {
+            path: device.path.clone(),
+            brightness: 50,
+            multi_intensity: None,
+            class: None,
+            max_brightness,
+            saved_at: None,
+            percent: percent.map(|percent| Percent::new(percent).unwrap()),
+        };
+        assert_eq!(saved_brightness(&data(None, None), &device), 50);
+        assert_eq!(saved_brightness(&data(None, Some(200)), &device), 50);
+        assert_eq!(saved_brightness(&data(None, Some(100)), &device), 90);
+        // The percentage is preferred over the raw value
+        assert_eq!(saved_brightness(&data(Some(50.0), Some(100)), &device), 14);
+
+        let json = r#"[{"path": "/sys/class/backlight/device", "brightness": 1, "percent": 101}]"#;
+        assert!(serde_json::from_str::<Vec<SaveData>>(json).is_err());
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        use std::time::Duration;
+
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(at(951_782_400)), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(at(1_710_936_420)), "2024-03-20T12:07:00Z");
+        assert_eq!(rfc3339(at(1_735_689_599)), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_save_data_from_csv_row() {
+        let data = SaveData::from_csv_row("/sys/class/backlight/acpi_video0,42").unwrap();
+        assert_eq!(data.path, PathBuf::from("/sys/class/backlight/acpi_video0"));
+        assert_eq!(data.brightness, 42);
+
+        let row = "acpi_video0,/sys/class/backlight/acpi_video0,backlight,42,100,81.16";
+        let data = SaveData::from_csv_row(row).unwrap();
+        assert_eq!(data.path, PathBuf::from("/sys/class/backlight/acpi_video0"));
+        assert_eq!(data.brightness, 42);
+        assert_eq!(data.class, Some(Class::Backlight));
+        assert_eq!(data.max_brightness, Some(100));
+
+        assert!(SaveData::from_csv_row("/sys/class/backlight/acpi_video0").is_err());
+        assert!(SaveData::from_csv_row("/sys/class/backlight/acpi_video0,bright").is_err());
+        assert!(SaveData::from_csv_row("a,b,c").is_err());
+    }
+
+    #[test]
+    fn test_output_without_color() {
+        let devices = || {
+            [Class::Backlight, Class::Leds]
+                .into_iter()
+                .map(|class| Device {
+                    name: "device".to_string(),
+                    path: PathBuf::from("/sys/class/device"),
+                    class,
+                    brightness: 5,
+                    max_brightness: 10,
+                    multi_intensity: None,
+                    device_type: Some("raw".to_string()),
+                    scale: Some("non-linear".to_string()),
+                    trigger: None,
+                })
+        };
+        for format in OutputFormat::value_variants() {
+            let mut output = Vec::new();
+            let options = WriteOptions {
+                summary: true,
+                precision: DEFAULT_PRECISION,
+                scale: None,
+                no_color: false,
+                show_math: true,
+            };
+            format.write(&mut output, devices(), options).unwrap();
+            assert!(!output.is_empty());
+            assert!(!output.contains(&b'\x1b'), "{}", String::from_utf8_lossy(&output));
+        }
+
+        let mut output = Vec::new();
+        let options = WriteOptions {
+            summary: false,
+            precision: DEFAULT_PRECISION,
+            scale: None,
+            no_color: true,
+            show_math: false,
+        };
+        OutputFormat::JsonLines
+            .write(&mut output, devices(), options)
+            .unwrap();
+        let lines: Vec<_> = str::from_utf8(&output).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            serde_json::from_str::<serde_json::Value>(line).unwrap();
+        }
+
+        let mut output = Vec::new();
+        OutputFormat::CompactJson
+            .write(&mut output, devices(), options)
+            .unwrap();
+        let output = str::from_utf8(&output).unwrap();
+        assert!(!output.contains("null"), "{output}");
+        assert!(!output.contains(r#""trigger""#));
+        assert!(output.contains(r#""type":"raw""#));
+    }
+
+    #[test]
+    fn test_write_percent() {
+        let write = |format: OutputFormat, percent| {
+            let mut output = Vec::new();
+            let percent = Percent::new(percent).unwrap();
+            let percent_format = PercentFormat {
+                precision: 0,
+                decimal_comma: false,
+            };
+            format
+                .write_percent(&mut output, percent, percent_format)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(write(OutputFormat::Plain, 65.0), "65\n");
+        assert_eq!(write(OutputFormat::Json, 65.0), "{\"percent\":65.0}\n");
+        assert_eq!(write(OutputFormat::Pango, 65.0), "<span foreground='#ffcc00'>65%</span>\n");
+        assert_eq!(write(OutputFormat::Pango, 10.0), "<span foreground='#5f87af'>10%</span>\n");
+        let format = PercentFormat {
+            precision: 2,
+            decimal_comma: true,
+        };
+        assert_eq!(format.format(Percent::new(65.0).unwrap()), "65,00");
+        assert_eq!(pango_escape("a<b>&'c'"), "a&lt;b&gt;&amp;&apos;c&apos;");
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        let bar = |percent, width| progress_bar(Percent::new(percent).unwrap(), width);
+        assert_eq!(bar(40.0, 10), "[████░░░░░░]");
+        assert_eq!(bar(0.0, 4), "[░░░░]");
+        assert_eq!(bar(100.0, 4), "[████]");
+        assert_eq!(bar(50.0, 0), "[]");
+    }
+
+    #[test]
+    fn test_write_calibration() {
+        let mut output = Vec::new();
+        write_calibration(anstream::StripStream::new(&mut output), 10, Curve::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 103);
+        assert_eq!(lines[1], "0\t0");
+        assert_eq!(lines[100], "99\t10 *");
+        assert_eq!(lines[101], "100\t10 *");
+        // Only 11 raw values for 101 percentages
+        assert!(lines[102].starts_with("# 11 of 101"), "{}", lines[102]);
+        assert_eq!(lines.iter().filter(|line| line.ends_with('*')).count(), 90);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = device::test::mock_root("write_atomic");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("save.json");
+        write_atomic(&path, b"[]").unwrap();
+        write_atomic(&path, b"[1]").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[1]");
+
+        // Can't replace a directory, the temporary file is cleaned up
+        let path = dir.join("directory");
+        fs::create_dir_all(path.join("child")).unwrap();
+        assert!(write_atomic(&path, b"[]").is_err());
+        let mut entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["directory", "save.json"]);
+    }
+
+    #[test]
+    fn test_formula() {
+        use assert_float_eq::assert_float_absolute_eq;
+
+        let formula = Formula::new(Scale::Perceptual, 100).unwrap();
+        assert_float_absolute_eq!(formula.log10_max_brightness, 2.0);
+        assert_float_absolute_eq!(formula.percent_per_decade, 50.0);
+        assert_eq!(Formula::new(Scale::Linear, 100), None);
+        assert_eq!(Formula::new(Scale::Perceptual, 1), None);
+    }
+
+    #[test]
+    fn test_device_summary() {
+        assert_eq!(device_summary(2, 1, 0), "# 3 devices (2 backlight, 1 led)");
+        assert_eq!(device_summary(1, 0, 0), "# 1 device (1 backlight)");
+        assert_eq!(device_summary(0, 2, 1), "# 3 devices (2 leds, 1 display)");
+        assert_eq!(device_summary(0, 0, 0), "# 0 devices");
+    }
+
     #[test]
     fn test_brightness_from_percent() {
-        assert_eq!(brightness_from_percent(&Percent::new(0.0).unwrap(), 100), 0);
-        assert_eq!(brightness_from_percent(&Percent::new(10.0).unwrap(), 100), 2);
-        assert_eq!(brightness_from_percent(&Percent::new(20.0).unwrap(), 100), 3);
-        assert_eq!(brightness_from_percent(&Percent::new(30.0).unwrap(), 100), 4);
-        assert_eq!(brightness_from_percent(&Percent::new(40.0).unwrap(), 100), 6);
-        assert_eq!(brightness_from_percent(&Percent::new(50.0).unwrap(), 100), 10);
-        assert_eq!(brightness_from_percent(&Percent::new(60.0).unwrap(), 100), 16);
-        assert_eq!(brightness_from_percent(&Percent::new(70.0).unwrap(), 100), 25);
-        assert_eq!(brightness_from_percent(&Percent::new(80.0).unwrap(), 100), 40);
-        assert_eq!(brightness_from_percent(&Percent::new(90.0).unwrap(), 100), 63);
-        assert_eq!(brightness_from_percent(&Percent::new(95.0).unwrap(), 100), 79);
-        assert_eq!(brightness_from_percent(&Percent::new(99.0).unwrap(), 100), 95);
-        assert_eq!(brightness_from_percent(&Percent::new(100.0).unwrap(), 100), 100);
-        assert_eq!(brightness_from_percent(&Percent::new(100.0).unwrap(), 12345), 12345);
+        let from_percent = |percent: f32, max: Brightness, rounding: Rounding| {
+            brightness_from_percent(
+                &Percent::new(percent).unwrap(),
+                max,
+                rounding,
+                Curve::default(),
+            )
+        };
+
+        use Rounding::{Down, Nearest, Up};
+        assert_eq!(from_percent(0.0, 100, Nearest), 0);
+        assert_eq!(from_percent(10.0, 100, Nearest), 2);
+        assert_eq!(from_percent(20.0, 100, Nearest), 3);
+        assert_eq!(from_percent(30.0, 100, Nearest), 4);
+        assert_eq!(from_percent(40.0, 100, Nearest), 6);
+        assert_eq!(from_percent(50.0, 100, Nearest), 10);
+        assert_eq!(from_percent(60.0, 100, Nearest), 16);
+        assert_eq!(from_percent(70.0, 100, Nearest), 25);
+        assert_eq!(from_percent(80.0, 100, Nearest), 40);
+        assert_eq!(from_percent(90.0, 100, Nearest), 63);
+        assert_eq!(from_percent(95.0, 100, Nearest), 79);
+        assert_eq!(from_percent(99.0, 100, Nearest), 95);
+        assert_eq!(from_percent(100.0, 100, Nearest), 100);
+        assert_eq!(from_percent(100.0, 12345, Nearest), 12345);
+        assert_eq!(from_percent(50.0, 100000, Nearest), 316);
+        assert_eq!(from_percent(100.0, 100000, Nearest), 100000);
+
+        assert_eq!(from_percent(10.0, 100, Up), 2);
+        assert_eq!(from_percent(10.0, 100, Down), 1);
+        assert_eq!(from_percent(40.0, 100, Up), 7);
+        assert_eq!(from_percent(40.0, 100, Down), 6);
+        assert_eq!(from_percent(0.0, 100, Up), 0);
+        assert_eq!(from_percent(100.0, 12345, Up), 12345);
+        assert_eq!(from_percent(100.0, 12345, Down), 12345);
+
+        let curve = Curve {
+            ceiling: Percent::new(80.0).unwrap(),
+            ..Default::default()
+        };
+        let from_percent = |percent: f32, max: Brightness| {
+            brightness_from_percent(&Percent::new(percent).unwrap(), max, Nearest, curve)
+        };
+        assert_eq!(from_percent(0.0, 100), 0);
+        assert_eq!(from_percent(50.0, 100), 8);
+        assert_eq!(from_percent(100.0, 100), 80);
+        assert_eq!(from_percent(100.0, 12345), 9876);
+        // Adding at the ceiling doesn't move past it
+        let step = Percent::new(5.0).unwrap();
+        let resolved = resolve_brightness(80, 100, UpdateAction::Add, step, None, curve);
+        assert_eq!(resolved.brightness, 80);
+
+        let curve = Curve {
+            gamma: 2.0,
+            ..Default::default()
+        };
+        let from_percent = |percent: f32, max: Brightness| {
+            brightness_from_percent(&Percent::new(percent).unwrap(), max, Nearest, curve)
+        };
+        assert_eq!(from_percent(0.0, 10000), 0);
+        assert_eq!(from_percent(50.0, 10000), 10); // 25% without gamma
+        assert_eq!(from_percent(100.0, 10000), 10000);
+        for brightness in [10, 100, 5000] {
+            let percent = curve.percent(brightness, 10000);
+            assert_eq!(from_percent(percent.get(), 10000), brightness);
+        }
+
+        let table = LookupTable::new(vec![(0.0, 1), (50.0, 20), (100.0, 200)]).unwrap();
+        let curve = Curve {
+            table: Some(&table),
+            ..Default::default()
+        };
+        let from_percent = |percent: f32, max: Brightness| {
+            brightness_from_percent(&Percent::new(percent).unwrap(), max, Nearest, curve)
+        };
+        assert_eq!(from_percent(0.0, 255), 1);
+        assert_eq!(from_percent(25.0, 255), 11);
+        assert_eq!(from_percent(75.0, 255), 110);
+        assert_eq!(from_percent(100.0, 150), 150);
+        assert_eq!(curve.percent(110, 255), Percent::new(75.0).unwrap());
+    }
+
+    #[test]
+    fn test_percent_round_trip() {
+        for max in [1, 2, 7, 100, 255, 937, 12345, 21333, 100000] {
+            for i in 0..=200 {
+                let percent = Percent::new(i as f32 * 0.5).unwrap();
+                let brightness =
+                    brightness_from_percent(&percent, max, Rounding::Nearest, Curve::default());
+                let round_trip = brightness_to_percent(brightness, max);
+
+                // The percentage can only be recovered up to the resolution of the device,
+                // so it must be closer to the rounded brightness than to its neighbors.
+                let lower = brightness_to_percent(brightness.saturating_sub(1), max);
+                let upper = brightness_to_percent((brightness + 1).min(max), max);
+                assert!(
+                    lower.get() <= percent.get() && percent.get() <= upper.get(),
+                    "max = {max}, percent = {percent}, round trip = {round_trip}"
+                );
+
+                // Converting again gives back the same brightness. Both 0 and 1 map to 0%,
+                // so the lowest non-zero brightness can't be recovered.
+                if brightness > 1 {
+                    let again = brightness_from_percent(
+                        &round_trip,
+                        max,
+                        Rounding::Nearest,
+                        Curve::default(),
+                    );
+                    assert_eq!(again, brightness, "max = {max}, percent = {percent}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_linear_scale() {
+        assert_eq!(Scale::Linear.percent(0, 100), Percent::MIN);
+        assert_eq!(Scale::Linear.percent(25, 100), Percent::new(25.0).unwrap());
+        assert_eq!(Scale::Linear.percent(100, 100), Percent::MAX);
+        assert_eq!(Scale::Linear.percent(0, 0), Percent::MIN);
+        assert_eq!(Scale::Perceptual.percent(10, 100), Percent::new(50.0).unwrap());
+
+        let linear = Curve {
+            scale: Scale::Linear,
+            ..Curve::default()
+        };
+        let half = Percent::new(50.0).unwrap();
+        assert_eq!(brightness_from_percent(&half, 255, Rounding::Nearest, linear), 128);
+        assert_eq!(linear.percent(51, 255), Percent::new(20.0).unwrap());
     }
 
     #[test]
@@ -587,5 +3584,13 @@ mod test {
         assert_float_absolute_eq!(brightness_to_percent(95, 100).get(), 98.88, ep);
         assert_float_absolute_eq!(brightness_to_percent(100, 100).get(), 100.0, ep);
         assert_float_absolute_eq!(brightness_to_percent(12345, 12345).get(), 100.0, ep);
+        assert_float_absolute_eq!(brightness_to_percent(316, 100000).get(), 49.99, ep);
+        assert_float_absolute_eq!(brightness_to_percent(100000, 100000).get(), 100.0, ep);
+
+        for max in [1, 3, 7, 100, 255, 937, 7500, 21333, 120000, Brightness::MAX] {
+            let percent = brightness_to_percent(max, max);
+            assert_eq!(percent, Percent::MAX, "max = {max}");
+            assert_eq!(format!("{percent:.2}"), "100.00");
+        }
     }
 }
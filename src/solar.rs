@@ -0,0 +1,128 @@
+//! Approximate solar position, accurate enough to drive a brightness curve.
+//!
+//! Formulas from the [Astronomical Almanac's low precision algorithm][almanac].
+//!
+//! [almanac]: https://en.wikipedia.org/wiki/Position_of_the_Sun
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch at J2000.0 (2000-01-01 12:00 UTC).
+const J2000_UNIX: f64 = 946_728_000.0;
+
+/// Elevation of the sun at the end of civil twilight, in degrees.
+const TWILIGHT: f64 = -6.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Coordinates {
+    /// Latitude in degrees, positive to the north.
+    pub latitude: f64,
+    /// Longitude in degrees, positive to the east.
+    pub longitude: f64,
+}
+
+/// Returns the elevation of the sun (in degrees) and its declination at the given time.
+fn position(coords: Coordinates, time: SystemTime) -> (f64, f64) {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(err) => -err.duration().as_secs_f64(),
+    };
+    let days = (secs - J2000_UNIX) / 86400.0;
+
+    let mean_anomaly = (357.529 + 0.985_600_28 * days).to_radians();
+    let mean_longitude = 280.459 + 0.985_647_36 * days;
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.000_000_36 * days).to_radians();
+
+    let right_ascension =
+        f64::atan2(obliquity.cos() * ecliptic_longitude.sin(), ecliptic_longitude.cos());
+    let declination = (obliquity.sin() * ecliptic_longitude.sin())
+        .clamp(-1.0, 1.0)
+        .asin();
+
+    let sidereal_time = (18.697_374_558 + 24.065_709_824_419_08 * days) * 15.0;
+    let hour_angle = (sidereal_time + coords.longitude).to_radians() - right_ascension;
+
+    let latitude = coords.latitude.to_radians();
+    // Rounding can push the sine just past 1 when the sun is overhead, which `asin`
+    // turns into NaN.
+    let elevation = (latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos())
+    .clamp(-1.0, 1.0)
+    .asin();
+
+    (elevation.to_degrees(), declination.to_degrees())
+}
+
+/// Returns how much daylight there is at the given time, from 0 (night, after civil
+/// twilight) to 1 (solar noon).
+pub fn daylight(coords: Coordinates, time: SystemTime) -> f64 {
+    let (elevation, declination) = position(coords, time);
+    let noon_elevation = 90.0 - (coords.latitude - declination).abs();
+    if noon_elevation <= TWILIGHT {
+        // Polar night
+        return 0.0;
+    }
+    ((elevation - TWILIGHT) / (noon_elevation - TWILIGHT)).clamp(0.0, 1.0)
+}
+
+fn parse_degrees(s: &str, limit: f64) -> Result<f64, String> {
+    let degrees = s.parse::<f64>().map_err(|_| "not a number".to_string())?;
+    if degrees.is_finite() && degrees.abs() <= limit {
+        Ok(degrees)
+    } else {
+        Err(format!("not a value between -{limit} and {limit}"))
+    }
+}
+
+pub fn latitude_parser(s: &str) -> Result<f64, String> {
+    parse_degrees(s, 90.0)
+}
+
+pub fn longitude_parser(s: &str) -> Result<f64, String> {
+    parse_degrees(s, 180.0)
+}
+
+#[test]
+fn test_daylight() {
+    use std::time::Duration;
+
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    let equator = Coordinates {
+        latitude: 0.0,
+        longitude: 0.0,
+    };
+    // 2024-03-20 12:07 UTC, March equinox at solar noon on the prime meridian.
+    assert!(daylight(equator, at(1_710_936_420)) > 0.99);
+    // 2024-03-20 00:07 UTC, midnight.
+    assert_eq!(daylight(equator, at(1_710_893_220)), 0.0);
+
+    let new_york = Coordinates {
+        latitude: 40.7,
+        longitude: -74.0,
+    };
+    // 2024-06-21 16:55 UTC, around solar noon in New York.
+    assert!(daylight(new_york, at(1_718_988_900)) > 0.99);
+    // 2024-06-21 13:00 UTC, mid-morning.
+    let morning = daylight(new_york, at(1_718_974_800));
+    assert!(morning > 0.3 && morning < 0.9, "{morning}");
+}
+
+#[test]
+fn test_daylight_is_a_ratio() {
+    use std::time::Duration;
+
+    // Every 7 hours over a year, so each hour of the day is covered.
+    for hours in (0..24 * 365).step_by(7) {
+        let time = UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hours * 3600);
+        for latitude in -180..=180 {
+            let coords = Coordinates {
+                latitude: latitude as f64 / 2.0,
+                longitude: 0.0,
+            };
+            let daylight = daylight(coords, time);
+            assert!((0.0..=1.0).contains(&daylight), "{daylight} at {coords:?}");
+        }
+    }
+}
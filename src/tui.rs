@@ -0,0 +1,152 @@
+//! Minimal interactive terminal interface to adjust the brightness of devices.
+
+use std::error::Error;
+use std::io::{self, Write};
+
+use crossterm::cursor::MoveTo;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::queue;
+use crossterm::terminal::{self, Clear, ClearType};
+
+use crate::colors::{BOLD, CYAN, MAGENTA, Reset as R};
+use crate::device::{BrightnessBackend, Device};
+use crate::percent::Percent;
+use crate::{Curve, Scale, UpdateAction, new_brightness};
+
+const STEP: Percent = Percent::new(5.0).unwrap();
+const BAR_WIDTH: usize = 40;
+
+/// Puts the terminal in raw mode, restoring the previous mode when dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        _ = terminal::disable_raw_mode();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Up,
+    Down,
+    Tab,
+    Quit,
+    Other,
+}
+
+impl From<Event> for Key {
+    fn from(event: Event) -> Self {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event
+        else {
+            return Self::Other;
+        };
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => Self::Quit,
+            KeyCode::Char('q') | KeyCode::Esc => Self::Quit,
+            KeyCode::Up | KeyCode::Char('k') => Self::Up,
+            KeyCode::Down | KeyCode::Char('j') => Self::Down,
+            KeyCode::Tab => Self::Tab,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Clear the screen and move the cursor to the top-left corner.
+///
+/// Goes to the raw `screen` since `anstream` strips escape sequences when colors are
+/// disabled.
+fn clear(screen: &mut impl Write) -> io::Result<()> {
+    queue!(screen, Clear(ClearType::All), MoveTo(0, 0))?;
+    screen.flush()
+}
+
+fn render(
+    screen: &mut impl Write,
+    output: &mut impl Write,
+    devices: &[Device],
+    selected: usize,
+    status: &str,
+) -> io::Result<()> {
+    clear(screen)?;
+    // In raw mode `\n` doesn't return the carriage, so lines end in `\r\n`.
+    for (i, device) in devices.iter().enumerate() {
+        let percent =
+            Scale::of_class(device.class).percent(device.brightness, device.max_brightness);
+        let filled = (percent.get() / 100.0 * BAR_WIDTH as f32).round() as usize;
+        let (marker, style) = if i == selected {
+            (">", BOLD)
+        } else {
+            (" ", crate::colors::NONE)
+        };
+        write!(
+            output,
+            "{marker} {style}{MAGENTA}{}{R}\r\n  [{CYAN}{}{R}{}] {percent:6.2}\r\n",
+            device.name,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+        )?;
+    }
+    write!(output, "\r\n{status}\r\nUp/Down: adjust  Tab: next device  q: quit\r\n")?;
+    output.flush()
+}
+
+/// Run the interface until the user quits.
+pub fn run(
+    mut devices: Vec<Device>,
+    backend: &dyn BrightnessBackend,
+) -> Result<(), Box<dyn Error>> {
+    let _raw_mode = RawMode::enable()?;
+    let mut screen = io::stdout();
+    let mut output = anstream::stdout();
+    let mut selected = 0;
+    let mut status = String::new();
+
+    loop {
+        render(&mut screen, &mut output, &devices, selected, &status)?;
+        let action = match Key::from(event::read()?) {
+            Key::Up => UpdateAction::Add,
+            Key::Down => UpdateAction::Sub,
+            Key::Tab => {
+                selected = (selected + 1) % devices.len();
+                continue;
+            }
+            Key::Quit => break,
+            Key::Other => continue,
+        };
+        let device = &mut devices[selected];
+        let curve = Curve {
+            scale: Scale::of_class(device.class),
+            ..Curve::default()
+        };
+        let brightness = new_brightness(device, action, STEP, None, curve).brightness;
+        status = match backend.set(device, brightness) {
+            Ok(_) => String::new(),
+            Err(err) => format!("error: {err}"),
+        };
+    }
+
+    clear(&mut screen)?;
+    Ok(())
+}
+
+#[test]
+fn test_key() {
+    let key = |code, modifiers| Key::from(Event::Key(KeyEvent::new(code, modifiers)));
+    assert_eq!(key(KeyCode::Up, KeyModifiers::NONE), Key::Up);
+    assert_eq!(key(KeyCode::Char('j'), KeyModifiers::NONE), Key::Down);
+    assert_eq!(key(KeyCode::Char('c'), KeyModifiers::CONTROL), Key::Quit);
+    assert_eq!(key(KeyCode::Char('c'), KeyModifiers::NONE), Key::Other);
+    assert_eq!(Key::from(Event::FocusGained), Key::Other);
+}